@@ -0,0 +1,21 @@
+#![allow(dead_code)] // Due to criterion we need this to avoid warnings.
+
+use criterion::Criterion;
+use std::time::Duration;
+
+mod suites;
+
+fn main() {
+    let mut c = Criterion::default()
+        // Configure defaults before overriding with args.
+        .warm_up_time(Duration::from_millis(500))
+        .measurement_time(Duration::from_secs(1))
+        .configure_from_args();
+
+    suites::bench_unstable(&mut c);
+    suites::bench_raft_log(&mut c);
+    suites::bench_storage(&mut c);
+    suites::bench_message(&mut c);
+
+    c.final_summary();
+}
@@ -0,0 +1,101 @@
+use criterion::{BatchSize, Criterion};
+use hello::log_unstable::Unstable;
+use hello::raft_log::RaftLog;
+use hello::storage::MemStorage;
+
+use super::{discard_logger, new_entries};
+
+pub fn bench_unstable(c: &mut Criterion) {
+    bench_unstable_truncate_and_append(c);
+}
+
+pub fn bench_raft_log(c: &mut Criterion) {
+    bench_raft_log_append(c);
+    bench_raft_log_slice(c);
+    bench_raft_log_term(c);
+}
+
+fn bench_unstable_truncate_and_append(c: &mut Criterion) {
+    for &batch in &[1u64, 16, 256] {
+        let ents = new_entries(1001, batch, 2, 128);
+        c.bench_function(
+            &format!("Unstable::truncate_and_append append {}", batch),
+            |b| {
+                b.iter_batched(
+                    || {
+                        let mut u = Unstable::new(1, discard_logger());
+                        u.truncate_and_append(&new_entries(1, 1000, 1, 128));
+                        u
+                    },
+                    |mut u| u.truncate_and_append(&ents),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+
+        // 从中间开始覆盖已有的日志
+        let ents = new_entries(501, batch, 2, 128);
+        c.bench_function(
+            &format!("Unstable::truncate_and_append truncate {}", batch),
+            |b| {
+                b.iter_batched(
+                    || {
+                        let mut u = Unstable::new(1, discard_logger());
+                        u.truncate_and_append(&new_entries(1, 1000, 1, 128));
+                        u
+                    },
+                    |mut u| u.truncate_and_append(&ents),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+}
+
+/// 前 1000 条日志在 Storage 中，之后的 1000 条在 unstable 中
+fn new_raft_log() -> RaftLog<MemStorage> {
+    let store = MemStorage::new();
+    store.wl().append(&new_entries(1, 1000, 1, 128)).unwrap();
+    let mut raft_log = RaftLog::new(store, discard_logger());
+    raft_log.append(&new_entries(1001, 1000, 2, 128));
+    raft_log
+}
+
+fn bench_raft_log_append(c: &mut Criterion) {
+    for &batch in &[1u64, 16, 256] {
+        let ents = new_entries(2001, batch, 2, 128);
+        c.bench_function(&format!("RaftLog::append {}", batch), |b| {
+            b.iter_batched(
+                new_raft_log,
+                |mut raft_log| raft_log.append(&ents),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+}
+
+fn bench_raft_log_slice(c: &mut Criterion) {
+    let raft_log = new_raft_log();
+    c.bench_function("RaftLog::slice storage", |b| {
+        b.iter(|| raft_log.slice(100, 200, None).unwrap())
+    });
+    c.bench_function("RaftLog::slice unstable", |b| {
+        b.iter(|| raft_log.slice(1100, 1200, None).unwrap())
+    });
+    c.bench_function("RaftLog::slice across", |b| {
+        b.iter(|| raft_log.slice(950, 1050, None).unwrap())
+    });
+    c.bench_function("RaftLog::slice max_size", |b| {
+        b.iter(|| raft_log.slice(950, 1050, 16 * 1024).unwrap())
+    });
+}
+
+fn bench_raft_log_term(c: &mut Criterion) {
+    let raft_log = new_raft_log();
+    c.bench_function("RaftLog::term storage", |b| {
+        b.iter(|| raft_log.term(500).unwrap())
+    });
+    c.bench_function("RaftLog::term unstable", |b| {
+        b.iter(|| raft_log.term(1500).unwrap())
+    });
+}
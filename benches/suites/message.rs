@@ -0,0 +1,24 @@
+use criterion::Criterion;
+use hello::protos::eraftpb::{Message, MessageType};
+use protobuf::{Message as PbMessage, RepeatedField};
+
+use super::new_entries;
+
+pub fn bench_message(c: &mut Criterion) {
+    for &(count, size) in &[(1u64, 128usize), (64, 128), (1024, 128), (64, 16 * 1024)] {
+        let mut m = Message::default();
+        m.set_msg_type(MessageType::MsgAppend);
+        m.from = 1;
+        m.to = 2;
+        m.term = 2;
+        m.set_entries(RepeatedField::from_vec(new_entries(1, count, 2, size)));
+        let data = m.write_to_bytes().unwrap();
+
+        c.bench_function(&format!("Message::encode {}x{}", count, size), |b| {
+            b.iter(|| m.write_to_bytes().unwrap())
+        });
+        c.bench_function(&format!("Message::decode {}x{}", count, size), |b| {
+            b.iter(|| protobuf::parse_from_bytes::<Message>(&data).unwrap())
+        });
+    }
+}
@@ -0,0 +1,26 @@
+use hello::protos::eraftpb::Entry;
+use slog::{Discard, Logger};
+
+mod log;
+pub use self::log::*;
+mod storage;
+pub use self::storage::*;
+mod message;
+pub use self::message::*;
+
+/// 基准测试不需要输出日志
+pub fn discard_logger() -> Logger {
+    Logger::root(Discard, slog::o!())
+}
+
+/// 生成 [start, start + count) 之间、数据长度为 size 的日志条目
+pub fn new_entries(start: u64, count: u64, term: u64, size: usize) -> Vec<Entry> {
+    (start..start + count)
+        .map(|index| Entry {
+            index,
+            term,
+            data: vec![0; size],
+            ..Default::default()
+        })
+        .collect()
+}
@@ -0,0 +1,23 @@
+use criterion::Criterion;
+use hello::storage::{MemStorage, Storage};
+
+use super::new_entries;
+
+pub fn bench_storage(c: &mut Criterion) {
+    bench_mem_storage_entries(c);
+}
+
+fn bench_mem_storage_entries(c: &mut Criterion) {
+    let store = MemStorage::new();
+    store.wl().append(&new_entries(1, 10000, 1, 128)).unwrap();
+
+    for &max_size in &[None, Some(0), Some(4 * 1024), Some(1024 * 1024)] {
+        let name = match max_size {
+            None => "MemStorage::entries no limit".to_owned(),
+            Some(size) => format!("MemStorage::entries max_size {}", size),
+        };
+        c.bench_function(&name, |b| {
+            b.iter(|| store.entries(1000, 2000, max_size).unwrap())
+        });
+    }
+}
@@ -0,0 +1,25 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use hello::mq::MessageQueue;
+use hello::protos::eraftpb::Message;
+use hello::transport;
+
+fuzz_target!(|data: &[u8]| {
+    // 按照网络上的帧格式解码，解码失败只能返回错误
+    let mut r = data;
+    let m: Message = match transport::read_message(&mut r) {
+        Ok(Some(m)) => m,
+        _ => return,
+    };
+    // 日志条目中的数据同样来自网络，状态机不能因为损坏的数据崩溃
+    let mut mq = MessageQueue::new();
+    for e in m.get_entries() {
+        let applied = mq.applied_index();
+        match mq.apply(e) {
+            Ok(_) => assert_eq!(mq.applied_index(), e.index),
+            Err(_) => assert_eq!(mq.applied_index(), applied),
+        }
+        assert!(mq.applied_index() >= applied);
+    }
+});
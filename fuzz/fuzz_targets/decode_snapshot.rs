@@ -0,0 +1,15 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use hello::protos::eraftpb::Snapshot;
+use protobuf::Message as PbMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let m = match protobuf::parse_from_bytes::<Snapshot>(data) {
+        Ok(m) => m,
+        Err(_) => return,
+    };
+    // 能解码的数据重新编码之后必须得到相同的结果
+    let bytes = m.write_to_bytes().unwrap();
+    assert_eq!(protobuf::parse_from_bytes::<Snapshot>(&bytes).unwrap(), m);
+});
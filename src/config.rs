@@ -0,0 +1,162 @@
+use crate::errors::{Error, Result};
+use crate::raft::INVALID_ID;
+
+/// 启动一个 Raft 节点所需要的配置参数
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// 当前节点的ID，不能为 0
+    pub id: u64,
+    /// 选举超时的 tick 数，跟随者在这段时间内没有收到领导者的消息将发起选举。
+    /// 为了避免不必要的领导者切换，election_tick 必须大于 heartbeat_tick，
+    /// 建议设置为 heartbeat_tick 的 10 倍
+    pub election_tick: usize,
+    /// 心跳间隔的 tick 数，领导者每隔 heartbeat_tick 发送一次心跳
+    pub heartbeat_tick: usize,
+    /// 最后一次应用的日志下标，只有在重启节点时才需要设置
+    pub applied: u64,
+    /// 每条追加消息的最大长度，0 表示每条消息最多携带一个日志条目
+    pub max_size_per_msg: u64,
+    /// 乐观复制阶段最多允许的飞行中的追加消息数量
+    pub max_inflight_msgs: usize,
+    /// 如果为 true，领导者在选举超时内没有收到大多数节点的回复将退位
+    pub check_quorum: bool,
+    /// 如果为 true，将启用 PreVote 算法
+    pub pre_vote: bool,
+    /// 随机选举超时的下限，为 0 时使用 election_tick
+    pub min_election_tick: usize,
+    /// 随机选举超时的上限，为 0 时使用 2 * election_tick
+    pub max_election_tick: usize,
+    /// 节点的选举优先级，目前只会被保存以及记录到日志中。
+    /// 当前还没有处理投票的逻辑，设置该值不会影响选举结果
+    pub priority: u64,
+    /// 如果为 true，同一次 Ready 中发往同一节点的连续追加消息将会被合并，
+    /// 合并后的消息长度不超过 max_size_per_msg。
+    /// max_size_per_msg 为 0 时携带日志条目的追加消息都不会被合并
+    pub batch_append: bool,
+    /// 如果为 true，领导者提交日志后不再立即广播空的追加消息来推进跟随者的提交下标，
+    /// 而是由下一次追加或者心跳消息携带 commit。
+    /// 当前还没有广播提交下标的逻辑，该选项暂时不起作用
+    pub skip_bcast_commit: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        const HEARTBEAT_TICK: usize = 2;
+        Self {
+            id: 0,
+            election_tick: HEARTBEAT_TICK * 10,
+            heartbeat_tick: HEARTBEAT_TICK,
+            applied: 0,
+            max_size_per_msg: 0,
+            max_inflight_msgs: 256,
+            check_quorum: false,
+            pre_vote: false,
+            min_election_tick: 0,
+            max_election_tick: 0,
+            priority: 0,
+            batch_append: false,
+            skip_bcast_commit: false,
+        }
+    }
+}
+
+impl Config {
+    /// 创建一个带有默认值的配置
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            ..Self::default()
+        }
+    }
+
+    /// 随机选举超时的下限
+    #[inline]
+    pub fn min_election_tick(&self) -> usize {
+        if self.min_election_tick == 0 {
+            self.election_tick
+        } else {
+            self.min_election_tick
+        }
+    }
+
+    /// 随机选举超时的上限
+    #[inline]
+    pub fn max_election_tick(&self) -> usize {
+        if self.max_election_tick == 0 {
+            2 * self.election_tick
+        } else {
+            self.max_election_tick
+        }
+    }
+
+    /// 校验配置参数是否合法
+    pub fn validate(&self) -> Result<()> {
+        if self.id == INVALID_ID {
+            return Err(Error::ConfigInvalid("invalid node id".to_owned()));
+        }
+
+        if self.heartbeat_tick == 0 {
+            return Err(Error::ConfigInvalid(
+                "heartbeat tick must greater than 0".to_owned(),
+            ));
+        }
+
+        if self.election_tick <= self.heartbeat_tick {
+            return Err(Error::ConfigInvalid(
+                "election tick must be greater than heartbeat tick".to_owned(),
+            ));
+        }
+
+        let min_timeout = self.min_election_tick();
+        let max_timeout = self.max_election_tick();
+        if min_timeout < self.election_tick {
+            return Err(Error::ConfigInvalid(format!(
+                "min election tick {} must not be less than election_tick {}",
+                min_timeout, self.election_tick
+            )));
+        }
+
+        if min_timeout >= max_timeout {
+            return Err(Error::ConfigInvalid(format!(
+                "min election tick {} should be less than max election tick {}",
+                min_timeout, max_timeout
+            )));
+        }
+
+        if self.max_inflight_msgs == 0 {
+            return Err(Error::ConfigInvalid(
+                "max inflight messages must be greater than 0".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_validate() {
+        let mut c = Config::new(1);
+        assert_eq!(c.validate(), Ok(()));
+
+        c.priority = 10;
+        assert_eq!(c.validate(), Ok(()));
+
+        let tests: Vec<fn(&mut Config)> = vec![
+            |c| c.id = INVALID_ID,
+            |c| c.heartbeat_tick = 0,
+            |c| c.election_tick = c.heartbeat_tick,
+            |c| c.min_election_tick = c.election_tick - 1,
+            |c| c.max_election_tick = c.election_tick,
+            |c| c.max_inflight_msgs = 0,
+        ];
+        for (i, f) in tests.iter().enumerate() {
+            let mut c = Config::new(1);
+            f(&mut c);
+            assert!(c.validate().is_err(), "#{}: expect error", i);
+        }
+    }
+}
@@ -79,6 +79,10 @@ quick_error! {
         RequestSnapshotDropped {
             description("raft: request snapshot dropped")
         }
+        /// The requested range [lo, hi) is invalid or outside of the available range [first, last).
+        OutOfBounds { lo: u64, hi: u64, first: u64, last: u64 } {
+            display("raft: slice[{}, {}) out of bound[{}, {})", lo, hi, first, last)
+        }
     }
 }
 
@@ -93,6 +97,20 @@ impl cmp::PartialEq for Error {
             (&Error::StepLocalMsg, &Error::StepLocalMsg) => true,
             (&Error::ConfigInvalid(ref e1), &Error::ConfigInvalid(ref e2)) => e1 == e2,
             (&Error::RequestSnapshotDropped, &Error::RequestSnapshotDropped) => true,
+            (
+                &Error::OutOfBounds {
+                    lo,
+                    hi,
+                    first,
+                    last,
+                },
+                &Error::OutOfBounds {
+                    lo: lo2,
+                    hi: hi2,
+                    first: first2,
+                    last: last2,
+                },
+            ) => (lo, hi, first, last) == (lo2, hi2, first2, last2),
             _ => false,
         }
     }
@@ -184,6 +202,34 @@ mod tests {
             Error::StepPeerNotFound,
             Error::Store(StorageError::Compacted)
         );
+        assert_eq!(
+            Error::OutOfBounds {
+                lo: 1,
+                hi: 2,
+                first: 3,
+                last: 4
+            },
+            Error::OutOfBounds {
+                lo: 1,
+                hi: 2,
+                first: 3,
+                last: 4
+            }
+        );
+        assert_ne!(
+            Error::OutOfBounds {
+                lo: 1,
+                hi: 2,
+                first: 3,
+                last: 4
+            },
+            Error::OutOfBounds {
+                lo: 1,
+                hi: 2,
+                first: 3,
+                last: 5
+            }
+        );
     }
 
     #[test]
@@ -0,0 +1,252 @@
+//! 日志条目、消息、快照以及成员变更的单行文本表示。
+//!
+//! protobuf 生成的 `Debug` 输出包含大量默认值，不适合排查问题。这里只输出有意义的字段，
+//! 格式保持稳定，可以直接用于日志以及 grep，例如
+//! `MsgAppend 1->2 term=3 log=(3,10) ents=[11..15] commit=10`。
+
+use std::fmt::Write;
+
+use crate::protos::eraftpb::{
+    ConfChange, ConfChangeType, ConfState, Entry, Message, Snapshot, SnapshotMetadata,
+};
+
+/// 格式化单个日志条目，例如 `EntryNormal index=11 term=3 data=12B`
+pub fn format_entry(e: &Entry) -> String {
+    format!(
+        "{:?} index={} term={} data={}B",
+        e.get_entry_type(),
+        e.index,
+        e.term,
+        e.data.len()
+    )
+}
+
+/// 格式化连续日志条目的下标范围，例如 `[11..15]`，区间两端都包含在内
+pub fn format_entries(ents: &[Entry]) -> String {
+    match (ents.first(), ents.last()) {
+        (Some(first), Some(last)) if first.index == last.index => format!("[{}]", first.index),
+        (Some(first), Some(last)) => format!("[{}..{}]", first.index, last.index),
+        _ => "[]".to_owned(),
+    }
+}
+
+/// 格式化消息，只输出非默认值的字段，
+/// 例如 `MsgAppend 1->2 term=3 log=(3,10) ents=[11..15] commit=10`
+pub fn format_message(m: &Message) -> String {
+    let mut s = format!(
+        "{:?} {}->{} term={}",
+        m.get_msg_type(),
+        m.from,
+        m.to,
+        m.term
+    );
+    if m.log_term != 0 || m.index != 0 {
+        write!(s, " log=({},{})", m.log_term, m.index).unwrap();
+    }
+    if !m.get_entries().is_empty() {
+        write!(s, " ents={}", format_entries(m.get_entries())).unwrap();
+    }
+    if m.commit != 0 {
+        write!(s, " commit={}", m.commit).unwrap();
+    }
+    if m.reject {
+        write!(s, " reject hint={}", m.reject_hint).unwrap();
+    }
+    if m.has_snapshot() {
+        let meta = m.get_snapshot().get_metadata();
+        write!(s, " snap=({},{})", meta.term, meta.index).unwrap();
+    }
+    if m.request_snapshot != 0 {
+        write!(s, " request_snapshot={}", m.request_snapshot).unwrap();
+    }
+    s
+}
+
+/// 格式化集群成员，例如 `voters=[1,2,3] learners=[4]`
+pub fn format_conf_state(cs: &ConfState) -> String {
+    format!(
+        "voters={} learners={}",
+        format_ids(cs.get_nodes()),
+        format_ids(cs.get_learners())
+    )
+}
+
+/// 格式化快照元数据，例如 `Snapshot index=10 term=3 voters=[1,2,3] learners=[]`
+pub fn format_snapshot_metadata(meta: &SnapshotMetadata) -> String {
+    let mut s = format!(
+        "Snapshot index={} term={} {}",
+        meta.index,
+        meta.term,
+        format_conf_state(meta.get_conf_state())
+    );
+    if meta.has_next_conf_state() {
+        write!(
+            s,
+            " next=({}) next_index={}",
+            format_conf_state(meta.get_next_conf_state()),
+            meta.next_conf_state_index
+        )
+        .unwrap();
+    }
+    s
+}
+
+/// 格式化快照，在元数据之后附加数据的大小
+pub fn format_snapshot(snap: &Snapshot) -> String {
+    format!(
+        "{} data={}B",
+        format_snapshot_metadata(snap.get_metadata()),
+        snap.data.len()
+    )
+}
+
+/// 格式化成员变更，例如 `AddNode node=4`
+pub fn format_conf_change(cc: &ConfChange) -> String {
+    let mut s = format!("{:?} node={}", cc.get_change_type(), cc.node_id);
+    if cc.id != 0 {
+        write!(s, " id={}", cc.id).unwrap();
+    }
+    if cc.get_change_type() == ConfChangeType::BeginMembershipChange {
+        write!(s, " start_index={}", cc.start_index).unwrap();
+    }
+    if cc.has_configuration() {
+        write!(s, " {}", format_conf_state(cc.get_configuration())).unwrap();
+    }
+    s
+}
+
+fn format_ids(ids: &[u64]) -> String {
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    format!("[{}]", ids.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::eraftpb::{EntryType, MessageType};
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            ..Default::default()
+        }
+    }
+
+    fn new_conf_state(nodes: Vec<u64>, learners: Vec<u64>) -> ConfState {
+        ConfState {
+            nodes,
+            learners,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_format_entry() {
+        let mut e = new_entry(11, 3);
+        e.data = b"hello".to_vec();
+        assert_eq!(format_entry(&e), "EntryNormal index=11 term=3 data=5B");
+        e.set_entry_type(EntryType::EntryConfChange);
+        assert_eq!(format_entry(&e), "EntryConfChange index=11 term=3 data=5B");
+
+        assert_eq!(format_entries(&[]), "[]");
+        assert_eq!(format_entries(&[new_entry(11, 3)]), "[11]");
+        let ents: Vec<Entry> = (11..=15).map(|i| new_entry(i, 3)).collect();
+        assert_eq!(format_entries(&ents), "[11..15]");
+    }
+
+    #[test]
+    fn test_format_message() {
+        let mut m = Message {
+            from: 1,
+            to: 2,
+            term: 3,
+            log_term: 3,
+            index: 10,
+            commit: 10,
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgAppend);
+        m.set_entries((11..=15).map(|i| new_entry(i, 3)).collect());
+        assert_eq!(
+            format_message(&m),
+            "MsgAppend 1->2 term=3 log=(3,10) ents=[11..15] commit=10"
+        );
+
+        let mut m = Message {
+            from: 2,
+            to: 1,
+            term: 3,
+            index: 10,
+            reject: true,
+            reject_hint: 7,
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgAppendResponse);
+        assert_eq!(
+            format_message(&m),
+            "MsgAppendResponse 2->1 term=3 log=(0,10) reject hint=7"
+        );
+
+        let mut m = Message {
+            from: 1,
+            to: 3,
+            term: 4,
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgSnapshot);
+        m.mut_snapshot().mut_metadata().index = 20;
+        m.mut_snapshot().mut_metadata().term = 4;
+        assert_eq!(format_message(&m), "MsgSnapshot 1->3 term=4 snap=(4,20)");
+    }
+
+    #[test]
+    fn test_format_snapshot() {
+        let mut snap = Snapshot {
+            data: vec![0; 1024],
+            ..Default::default()
+        };
+        {
+            let meta = snap.mut_metadata();
+            meta.index = 10;
+            meta.term = 3;
+            meta.set_conf_state(new_conf_state(vec![1, 2, 3], vec![]));
+        }
+        assert_eq!(
+            format_snapshot(&snap),
+            "Snapshot index=10 term=3 voters=[1,2,3] learners=[] data=1024B"
+        );
+
+        let meta = snap.mut_metadata();
+        meta.set_next_conf_state(new_conf_state(vec![1, 2], vec![4]));
+        meta.next_conf_state_index = 9;
+        assert_eq!(
+            format_snapshot_metadata(meta),
+            "Snapshot index=10 term=3 voters=[1,2,3] learners=[] \
+             next=(voters=[1,2] learners=[4]) next_index=9"
+        );
+    }
+
+    #[test]
+    fn test_format_conf_change() {
+        let mut cc = ConfChange {
+            node_id: 4,
+            ..Default::default()
+        };
+        assert_eq!(format_conf_change(&cc), "AddNode node=4");
+        cc.id = 2;
+        cc.set_change_type(ConfChangeType::RemoveNode);
+        assert_eq!(format_conf_change(&cc), "RemoveNode node=4 id=2");
+
+        let mut cc = ConfChange {
+            start_index: 12,
+            ..Default::default()
+        };
+        cc.set_change_type(ConfChangeType::BeginMembershipChange);
+        cc.set_configuration(new_conf_state(vec![1, 2, 3], vec![4]));
+        assert_eq!(
+            format_conf_change(&cc),
+            "BeginMembershipChange node=0 start_index=12 voters=[1,2,3] learners=[4]"
+        );
+    }
+}
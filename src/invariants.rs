@@ -0,0 +1,173 @@
+//! Raft 安全性不变式检查，只在开启 `invariants` 特性时编译。
+//!
+//! 单个节点的检查（任期不回退、同一任期内不改投、提交下标不回退、`applied <= committed`、
+//! unstable 的 offset 与 Storage 一致）嵌入在 `Raft` 以及 `RaftLog` 中自动执行；
+//! 每个任期至多一个领导者需要观察整个集群，由测试或者模拟网络通过 `Leaders` 检查。
+
+use std::collections::HashMap;
+use std::fmt;
+
+use slog::Logger;
+
+/// 输出违反的不变式以及节点的完整状态，然后 panic
+pub fn violated(logger: &Logger, state: &str, msg: fmt::Arguments) -> ! {
+    crit!(logger, "invariant violated: {}", msg; "state" => state);
+    fatal!(logger, "invariant violated: {}, state: {}", msg, state)
+}
+
+/// 记录每个任期的领导者，保证每个任期至多只有一个领导者
+#[derive(Debug, Default)]
+pub struct Leaders {
+    leaders: HashMap<u64, u64>,
+}
+
+impl Leaders {
+    /// 创建一个空的记录
+    pub fn new() -> Leaders {
+        Leaders::default()
+    }
+
+    /// 记录节点 id 在任期 term 成为了领导者
+    ///
+    /// # Panics
+    ///
+    /// 如果该任期已经有其他节点成为了领导者
+    pub fn observe(&mut self, logger: &Logger, term: u64, id: u64) {
+        let leader = *self.leaders.entry(term).or_insert(id);
+        if leader != id {
+            violated(
+                logger,
+                &format!("term={}, leader={}", term, leader),
+                format_args!("two leaders {} and {} in term {}", leader, id, term),
+            )
+        }
+    }
+
+    /// 任期 term 的领导者
+    pub fn leader(&self, term: u64) -> Option<u64> {
+        self.leaders.get(&term).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::protos::eraftpb::{ConfState, Entry, Message};
+    use crate::raft::Raft;
+    use crate::storage::MemStorage;
+
+    fn new_raft() -> Raft<MemStorage> {
+        let l = crate::default_logger();
+        let mut r = Raft::new(&Config::new(1), MemStorage::new(), &l).unwrap();
+        r.term = 2;
+        r.vote = 1;
+        let ents: Vec<Entry> = (1..=3)
+            .map(|index| Entry {
+                index,
+                term: 2,
+                ..Default::default()
+            })
+            .collect();
+        r.raft_log.append(&ents);
+        r.raft_log.commit_to(2);
+        r.check_invariants();
+        r
+    }
+
+    #[test]
+    fn test_raft_invariants() {
+        let mut r = new_raft();
+        // 更高的任期可以重新投票
+        r.term = 3;
+        r.vote = 2;
+        r.send(Message::default());
+        r.raft_log.commit_to(3);
+        r.check_invariants();
+    }
+
+    #[test]
+    fn test_restart_invariants() {
+        let store = MemStorage::new_with_conf_state(ConfState {
+            nodes: vec![1],
+            ..Default::default()
+        });
+        {
+            let mut core = store.wl();
+            let ents: Vec<Entry> = (2..=3)
+                .map(|index| Entry {
+                    index,
+                    term: 1,
+                    ..Default::default()
+                })
+                .collect();
+            core.append(&ents).unwrap();
+            core.commit_to(3).unwrap();
+        }
+        let l = crate::default_logger();
+        let mut r = Raft::new(&Config::new(1), store, &l).unwrap();
+        r.send(Message::default());
+        assert_eq!(r.hard_state().commit, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "vote changed from 1 to 2 in term 2")]
+    fn test_vote_changed() {
+        let mut r = new_raft();
+        r.vote = 2;
+        r.send(Message::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "term regressed from 2 to 1")]
+    fn test_term_regressed() {
+        let mut r = new_raft();
+        r.term = 1;
+        r.check_invariants();
+    }
+
+    #[test]
+    #[should_panic(expected = "commit regressed from 2 to 1")]
+    fn test_commit_regressed() {
+        let mut r = new_raft();
+        r.raft_log.committed = 1;
+        r.check_invariants();
+    }
+
+    #[test]
+    #[should_panic(expected = "applied 3 > committed 2")]
+    fn test_applied_beyond_committed() {
+        let mut r = new_raft();
+        r.raft_log.applied = 3;
+        r.check_invariants();
+    }
+
+    #[test]
+    #[should_panic(expected = "unstable.offset 5 > storage last_index 0 + 1")]
+    fn test_unstable_offset() {
+        let mut r = new_raft();
+        r.raft_log.unstable.offset = 5;
+        r.check_invariants();
+    }
+
+    #[test]
+    fn test_leaders() {
+        let l = crate::default_logger();
+        let mut leaders = Leaders::new();
+        leaders.observe(&l, 1, 1);
+        leaders.observe(&l, 1, 1);
+        leaders.observe(&l, 2, 3);
+        assert_eq!(leaders.leader(1), Some(1));
+        assert_eq!(leaders.leader(2), Some(3));
+        assert_eq!(leaders.leader(3), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "two leaders 1 and 2 in term 1")]
+    fn test_two_leaders() {
+        let l = crate::default_logger();
+        let mut leaders = Leaders::new();
+        leaders.observe(&l, 1, 1);
+        leaders.observe(&l, 1, 2);
+    }
+}
@@ -1,4 +1,3 @@
-#[cfg(feature = "failpoints")]
 #[macro_use]
 extern crate fail;
 
@@ -6,6 +5,8 @@ extern crate fail;
 extern crate slog;
 #[macro_use]
 extern crate quick_error;
+#[macro_use]
+extern crate getset;
 
 mod util;
 
@@ -24,72 +25,74 @@ macro_rules! fatal {
     }};
 }
 
-mod raft_log;
+/// 开启 `invariants` 特性时检查安全性不变式，违反时先通过日志输出节点的完整状态再 panic。
+/// 没有开启特性时不会产生任何代码
+macro_rules! invariant {
+    ($logger:expr, $cond:expr, $state:expr, $fmt:expr, $($arg:tt)+) => {{
+        #[cfg(feature = "invariants")]
+        {
+            if !$cond {
+                crate::invariants::violated(&$logger, &$state, format_args!($fmt, $($arg)+))
+            }
+        }
+    }};
+}
+
+pub mod raft_log;
+
+#[cfg(feature = "invariants")]
+pub mod invariants;
 
-mod storage;
+pub mod storage;
 
-mod protos;
+pub mod protos;
 
-mod errors;
+pub mod errors;
 
 pub mod raft;
 
-mod log_unstable;
+pub mod config;
+
+pub mod transport;
+
+pub mod network;
+
+pub mod linearizability;
+
+pub mod mq;
+
+pub mod state_machine;
+
+pub mod log_unstable;
+
+pub mod logger;
+
+pub mod metrics;
+
+pub mod status;
+
+pub mod formatter;
 
 /// The default logger we fall back to when passed `None` in external facing constructors.
 ///
-/// Currently, this is a `log` adaptor behind a `Once` to ensure there is no clobbering.
+/// It is built once by `logger::LoggerBuilder`, filtered by `RUST_LOG`, and tagged with the
+/// name of the calling thread, which is the case name under `cargo test`.
 #[cfg(any(test, feature = "default-logger"))]
 pub fn default_logger() -> slog::Logger {
-    use slog::Drain;
-    use std::sync::{Mutex, Once};
-
-    static LOGGER_INITIALIZED: Once = Once::new();
-    static mut LOGGER: Option<slog::Logger> = None;
-
-    let logger = unsafe {
-        LOGGER_INITIALIZED.call_once(|| {
-            let decorator = slog_term::TermDecorator::new().build();
-            let drain = slog_term::CompactFormat::new(decorator).build();
-            let drain = slog_envlogger::new(drain);
-            LOGGER = Some(slog::Logger::root(Mutex::new(drain).fuse(), o!()));
-        });
-        LOGGER.as_ref().unwrap()
-    };
-    let case = std::thread::current()
-        .name()
-        .unwrap()
-        .split(':')
-        .last()
-        .unwrap()
-        .to_string();
-    logger.new(o!("case" => case))
-}
+    use std::sync::OnceLock;
 
-#[cfg(any(test, feature = "default_logger"))]
-pub fn default_loggger() -> slog::Logger {
-    use slog::Drain;
-    use std::sync::Mutex;
-    use std::sync::Once;
-
-    static LOGGER_INITIALIZED: Once = Once::new();
-    static mut LOGGER: Option<slog::Logger> = None;
-
-    let logger = unsafe {
-        LOGGER_INITIALIZED.call_once(|| {
-            let decorator = slog_term::TermDecorator::new().build();
-            let drain = slog_term::CompactFormat::new(decorator).build();
-            let drain = slog_envlogger::new(drain);
-            LOGGER = Some(slog::Logger::root(Mutex::new(drain).fuse(), o!()));
-        });
-        LOGGER.as_ref().unwrap()
+    static LOGGER: OnceLock<slog::Logger> = OnceLock::new();
+
+    let logger = LOGGER.get_or_init(|| {
+        logger::LoggerBuilder::new()
+            .level(slog::Level::Trace)
+            .env_filter(true)
+            .build()
+    });
+    let thread = std::thread::current();
+    let case = match thread.name() {
+        Some(name) => name.rsplit(':').next().unwrap_or(name).to_owned(),
+        None => format!("{:?}", thread.id()),
     };
-    let case = std::thread::current()
-        .name()
-        .unwrap()
-        .split(':')
-        .last()
-        .unwrap()
-        .to_string();
-    logger.new(o!("loggger-case"=>case))
+    logger.new(o!("case" => case))
 }
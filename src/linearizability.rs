@@ -0,0 +1,266 @@
+//! 线性一致性检查。
+//!
+//! 记录客户端每个操作的调用以及返回时间，然后在所有满足实时顺序的排列中搜索一个
+//! 能够被顺序模型解释的执行顺序，思路与 Knossos 使用的 Wing & Gong 算法一致。
+//! 没有返回的操作（例如客户端崩溃或者请求超时）可能生效也可能没有生效，
+//! 检查时两种情况都会被考虑。
+
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash;
+
+/// 顺序执行的参考模型
+pub trait Model: Clone + Eq + Hash {
+    /// 操作的输入
+    type Input;
+    /// 操作的输出
+    type Output;
+
+    /// 按顺序执行一次操作，`output` 为 `None` 表示操作没有返回。
+    /// 如果模型无法产生该输出则返回 `None`，否则返回执行后的状态
+    fn step(&self, input: &Self::Input, output: Option<&Self::Output>) -> Option<Self>;
+}
+
+/// 客户端的一次操作
+#[derive(Debug, Clone)]
+pub struct Operation<I, O> {
+    /// 发起操作的客户端
+    pub client: u64,
+    /// 操作的输入
+    pub input: I,
+    /// 调用时间
+    pub call: u64,
+    /// 返回时间以及输出，没有返回时为 `None`
+    pub ret: Option<(u64, O)>,
+}
+
+/// 客户端操作的历史记录，使用逻辑时钟记录调用跟返回的先后顺序
+#[derive(Debug, Clone)]
+pub struct History<I, O> {
+    /// 所有的操作，按照调用顺序排列
+    pub ops: Vec<Operation<I, O>>,
+    clock: u64,
+}
+
+impl<I, O> Default for History<I, O> {
+    fn default() -> Self {
+        History {
+            ops: vec![],
+            clock: 0,
+        }
+    }
+}
+
+impl<I, O> History<I, O> {
+    /// 创建一个空的历史记录
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一次调用，返回操作的编号
+    pub fn invoke(&mut self, client: u64, input: I) -> usize {
+        self.clock += 1;
+        self.ops.push(Operation {
+            client,
+            input,
+            call: self.clock,
+            ret: None,
+        });
+        self.ops.len() - 1
+    }
+
+    /// 记录操作的返回
+    ///
+    /// # Panics
+    ///
+    /// 如果该操作已经返回过
+    pub fn complete(&mut self, id: usize, output: O) {
+        self.clock += 1;
+        let op = &mut self.ops[id];
+        assert!(op.ret.is_none(), "operation {} completed twice", id);
+        op.ret = Some((self.clock, output));
+    }
+}
+
+/// 检查历史记录是否满足线性一致性
+pub fn check<M: Model>(init: M, history: &History<M::Input, M::Output>) -> bool {
+    let mut checker = Checker {
+        ops: &history.ops,
+        visited: HashSet::new(),
+    };
+    let mut done = vec![false; history.ops.len()];
+    checker.search(&init, &mut done)
+}
+
+struct Checker<'a, M: Model> {
+    ops: &'a [Operation<M::Input, M::Output>],
+    visited: HashSet<(Vec<bool>, M)>,
+}
+
+impl<'a, M: Model> Checker<'a, M> {
+    fn search(&mut self, state: &M, done: &mut Vec<bool>) -> bool {
+        // 所有已经返回的操作都已经线性化，剩余没有返回的操作可以视为没有生效
+        let min_ret = self
+            .ops
+            .iter()
+            .zip(done.iter())
+            .filter(|(_, &d)| !d)
+            .filter_map(|(op, _)| op.ret.as_ref().map(|(ret, _)| *ret))
+            .min();
+        let min_ret = match min_ret {
+            Some(r) => r,
+            None => return true,
+        };
+        if !self.visited.insert((done.clone(), state.clone())) {
+            return false;
+        }
+
+        for i in 0..self.ops.len() {
+            let op = &self.ops[i];
+            // 只有在最早返回的操作之前调用的操作才可能是下一个生效的操作
+            if done[i] || op.call > min_ret {
+                continue;
+            }
+            let next = match state.step(&op.input, op.ret.as_ref().map(|(_, o)| o)) {
+                Some(next) => next,
+                None => continue,
+            };
+            done[i] = true;
+            if self.search(&next, done) {
+                return true;
+            }
+            done[i] = false;
+        }
+        false
+    }
+}
+
+/// 单值寄存器的操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterOp {
+    /// 读取寄存器
+    Read,
+    /// 写入寄存器
+    Write(u64),
+}
+
+/// 单值寄存器模型，读操作的输出为读到的值，写操作的输出被忽略
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Register(pub Option<u64>);
+
+impl Model for Register {
+    type Input = RegisterOp;
+    type Output = Option<u64>;
+
+    fn step(&self, input: &RegisterOp, output: Option<&Option<u64>>) -> Option<Register> {
+        match *input {
+            RegisterOp::Read => match output {
+                Some(v) if *v != self.0 => None,
+                _ => Some(self.clone()),
+            },
+            RegisterOp::Write(v) => Some(Register(Some(v))),
+        }
+    }
+}
+
+/// 键值存储的操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum KvOp {
+    /// 写入键值
+    Put(Vec<u8>, Vec<u8>),
+    /// 读取键对应的值
+    Get(Vec<u8>),
+    /// 删除键
+    Delete(Vec<u8>),
+}
+
+/// 键值存储模型，`Get` 的输出为读到的值，其他操作的输出被忽略
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Kv(pub BTreeMap<Vec<u8>, Vec<u8>>);
+
+impl Model for Kv {
+    type Input = KvOp;
+    type Output = Option<Vec<u8>>;
+
+    fn step(&self, input: &KvOp, output: Option<&Option<Vec<u8>>>) -> Option<Kv> {
+        match input {
+            KvOp::Get(k) => match output {
+                Some(v) if v.as_ref() != self.0.get(k) => None,
+                _ => Some(self.clone()),
+            },
+            KvOp::Put(k, v) => {
+                let mut next = self.clone();
+                next.0.insert(k.clone(), v.clone());
+                Some(next)
+            }
+            KvOp::Delete(k) => {
+                let mut next = self.clone();
+                next.0.remove(k);
+                Some(next)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_linearizable() {
+        // 两个写操作并发，读操作可以看到任意一个
+        let mut h = History::new();
+        let w1 = h.invoke(1, RegisterOp::Write(1));
+        let w2 = h.invoke(2, RegisterOp::Write(2));
+        h.complete(w2, None);
+        let r = h.invoke(3, RegisterOp::Read);
+        h.complete(w1, None);
+        h.complete(r, Some(1));
+        assert!(check(Register::default(), &h));
+
+        // w1 返回之后才调用 w2，之后的读操作不能再读到 1
+        let mut h = History::new();
+        let w1 = h.invoke(1, RegisterOp::Write(1));
+        h.complete(w1, None);
+        let w2 = h.invoke(2, RegisterOp::Write(2));
+        h.complete(w2, None);
+        let r = h.invoke(3, RegisterOp::Read);
+        h.complete(r, Some(1));
+        assert!(!check(Register::default(), &h));
+    }
+
+    #[test]
+    fn test_register_pending() {
+        // 没有返回的写操作可能已经生效
+        let mut h = History::new();
+        h.invoke(1, RegisterOp::Write(1));
+        let r = h.invoke(2, RegisterOp::Read);
+        h.complete(r, Some(1));
+        let r = h.invoke(2, RegisterOp::Read);
+        h.complete(r, Some(1));
+        assert!(check(Register::default(), &h));
+
+        // 但是生效之后不能再读到旧值
+        let r = h.invoke(2, RegisterOp::Read);
+        h.complete(r, None);
+        assert!(!check(Register::default(), &h));
+    }
+
+    #[test]
+    fn test_kv_linearizable() {
+        let k = b"k".to_vec();
+        let mut h = History::new();
+        let p = h.invoke(1, KvOp::Put(k.clone(), b"v1".to_vec()));
+        h.complete(p, None);
+        let g = h.invoke(2, KvOp::Get(k.clone()));
+        let d = h.invoke(1, KvOp::Delete(k.clone()));
+        h.complete(g, Some(b"v1".to_vec()));
+        h.complete(d, None);
+        let g = h.invoke(2, KvOp::Get(k.clone()));
+        h.complete(g, None);
+        assert!(check(Kv::default(), &h));
+
+        let g = h.invoke(3, KvOp::Get(k.clone()));
+        h.complete(g, Some(b"v1".to_vec()));
+        assert!(!check(Kv::default(), &h));
+    }
+}
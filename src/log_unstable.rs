@@ -1,3 +1,4 @@
+use crate::errors::{Error, Result};
 use crate::protos::eraftpb::*;
 
 use slog::Logger;
@@ -44,7 +45,7 @@ impl Unstable {
     /// 根据下标索引获取对应的任期
     pub fn maybe_term(&self, idx: u64) -> Option<u64> {
         if idx < self.offset {
-            let snapshot = self.snapshot.as_ref().unwrap();
+            let snapshot = self.snapshot.as_ref()?;
             let meta = snapshot.get_metadata();
             if idx == meta.index {
                 Some(meta.term)
@@ -53,7 +54,7 @@ impl Unstable {
             }
         } else {
             self.maybe_last_index().and_then(|last| {
-                if idx < last {
+                if idx > last {
                     return None;
                 }
                 Some(self.entries[(idx - self.offset) as usize].term)
@@ -87,6 +88,12 @@ impl Unstable {
 
     /// 从给定的快照中还原，但是不解压
     pub fn restore(&mut self, snap: Snapshot) {
+        info!(
+            self.logger,
+            "restore unstable from snapshot";
+            "index" => snap.get_metadata().index,
+            "term" => snap.get_metadata().term,
+        );
         self.entries.clear();
         self.offset = snap.get_metadata().index + 1;
         self.snapshot = Some(snap);
@@ -112,6 +119,19 @@ impl Unstable {
         }
     }
 
+    /// 与 `truncate_and_append` 相同，但是日志不连续时返回错误而不是 panic
+    pub fn try_truncate_and_append(&mut self, ents: &[Entry]) -> Result<()> {
+        let after = match ents.first() {
+            Some(e) => e.index,
+            None => return Ok(()),
+        };
+        if after > self.offset {
+            self.check_outofbounds(self.offset, after)?;
+        }
+        self.truncate_and_append(ents);
+        Ok(())
+    }
+
     /// 从日志条目数据中返回从low到high的切片
     pub fn slice(&self, lo: u64, hi: u64) -> &[Entry] {
         self.must_check_outofbounds(lo, hi);
@@ -121,6 +141,27 @@ impl Unstable {
         &self.entries[l - off..h - off]
     }
 
+    /// 与 `slice` 相同，但是越界时返回错误而不是 panic
+    pub fn try_slice(&self, lo: u64, hi: u64) -> Result<&[Entry]> {
+        self.check_outofbounds(lo, hi)?;
+        let off = self.offset;
+        Ok(&self.entries[(lo - off) as usize..(hi - off) as usize])
+    }
+
+    /// 判断lo跟hi不越界，越界时返回 `Error::OutOfBounds`
+    pub fn check_outofbounds(&self, lo: u64, hi: u64) -> Result<()> {
+        let upper = self.offset + self.entries.len() as u64;
+        if lo > hi || lo < self.offset || hi > upper {
+            return Err(Error::OutOfBounds {
+                lo,
+                hi,
+                first: self.offset,
+                last: upper,
+            });
+        }
+        Ok(())
+    }
+
     /// 判断lo跟hi不越界
     pub fn must_check_outofbounds(&self, lo: u64, hi: u64) {
         if lo > hi {
@@ -144,6 +185,11 @@ impl Unstable {
 #[cfg(test)]
 mod test {
     use crate::log_unstable::*;
+    use crate::util::run_model_test;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use std::cmp;
+    use std::collections::BTreeMap;
 
     fn new_entry(index: u64, term: u64) -> Entry {
         let mut e = Entry::default();
@@ -263,9 +309,196 @@ mod test {
     }
 
     #[test]
-    fn test_default_loggger() {
-        let logger=crate::default_loggger();
-        assert_eq!(1,1);
-        error!(logger,"开始记录数据");
+    fn test_try_slice() {
+        let u = Unstable {
+            entries: vec![new_entry(5, 1), new_entry(6, 1), new_entry(7, 2)],
+            offset: 5,
+            snapshot: None,
+            logger: crate::default_logger(),
+        };
+        let out_of_bounds = |lo, hi| {
+            Err(Error::OutOfBounds {
+                lo,
+                hi,
+                first: 5,
+                last: 8,
+            })
+        };
+        let tests = vec![
+            (5, 8, Ok(&u.entries[..])),
+            (6, 7, Ok(&u.entries[1..2])),
+            (6, 6, Ok(&u.entries[1..1])),
+            (4, 6, out_of_bounds(4, 6)),
+            (6, 9, out_of_bounds(6, 9)),
+            (7, 6, out_of_bounds(7, 6)),
+        ];
+        for (i, (lo, hi, wres)) in tests.into_iter().enumerate() {
+            assert_eq!(u.try_slice(lo, hi), wres, "#{}", i);
+        }
+    }
+
+    #[test]
+    fn test_try_truncate_and_append() {
+        let mut u = Unstable {
+            entries: vec![new_entry(5, 1), new_entry(6, 1)],
+            offset: 5,
+            snapshot: None,
+            logger: crate::default_logger(),
+        };
+        // 与已有日志之间存在空洞
+        assert_eq!(
+            u.try_truncate_and_append(&[new_entry(8, 1)]),
+            Err(Error::OutOfBounds {
+                lo: 5,
+                hi: 8,
+                first: 5,
+                last: 7
+            })
+        );
+        assert_eq!(u.entries.len(), 2);
+        assert_eq!(u.try_truncate_and_append(&[]), Ok(()));
+
+        let ents = vec![new_entry(6, 2), new_entry(7, 2)];
+        assert_eq!(u.try_truncate_and_append(&ents), Ok(()));
+        assert_eq!(
+            u.entries,
+            vec![new_entry(5, 1), new_entry(6, 2), new_entry(7, 2)]
+        );
+        assert_eq!(u.try_truncate_and_append(&[new_entry(4, 3)]), Ok(()));
+        assert_eq!((u.offset, u.entries.len()), (4, 1));
+    }
+
+    /// Unstable 的参考模型，用下标到任期的映射表示日志
+    #[derive(Debug, Default)]
+    struct Model {
+        offset: u64,
+        entries: BTreeMap<u64, u64>,
+        snapshot: Option<(u64, u64)>,
+    }
+
+    impl Model {
+        fn term(&self, idx: u64) -> Option<u64> {
+            if let Some(&term) = self.entries.get(&idx) {
+                return Some(term);
+            }
+            match self.snapshot {
+                Some((index, term)) if index == idx && idx < self.offset => Some(term),
+                _ => None,
+            }
+        }
+
+        fn last_index(&self) -> Option<u64> {
+            match self.entries.keys().next_back() {
+                Some(&idx) => Some(idx),
+                None => self.snapshot.map(|(index, _)| index),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        TruncateAndAppend(u64, u64, u64),
+        StableTo(u64, u64),
+        StableSnapTo(u64),
+        Restore(u64, u64),
+    }
+
+    fn random_op(rng: &mut StdRng, m: &Model) -> Op {
+        let upper = m.offset + m.entries.len() as u64;
+        let lo = m.offset.saturating_sub(2);
+        // Raft 不会覆盖快照之前的日志
+        let min_after = m.snapshot.map_or(1, |(index, _)| index + 1);
+        match rng.gen_range(0, 10) {
+            0..=4 => Op::TruncateAndAppend(
+                rng.gen_range(cmp::max(lo, min_after), upper + 1),
+                rng.gen_range(1, 5),
+                rng.gen_range(1, 5),
+            ),
+            5..=7 => {
+                let idx = rng.gen_range(lo, upper + 2);
+                // 大多数时候使用正确的任期
+                let term = match m.term(idx) {
+                    Some(t) if rng.gen_bool(0.8) => t,
+                    _ => rng.gen_range(1, 5),
+                };
+                Op::StableTo(idx, term)
+            }
+            8 => Op::StableSnapTo(rng.gen_range(lo, upper + 1)),
+            _ => Op::Restore(upper + rng.gen_range(0, 5), rng.gen_range(1, 5)),
+        }
+    }
+
+    fn apply_op(u: &mut Unstable, m: &mut Model, op: &Op) {
+        match *op {
+            Op::TruncateAndAppend(after, n, term) => {
+                let ents: Vec<Entry> = (after..after + n).map(|i| new_entry(i, term)).collect();
+                u.truncate_and_append(&ents);
+                let _ = m.entries.split_off(&after);
+                for e in ents {
+                    m.entries.insert(e.index, e.term);
+                }
+                m.offset = cmp::min(m.offset, after);
+            }
+            Op::StableTo(idx, term) => {
+                u.stable_to(idx, term);
+                if idx >= m.offset && m.term(idx) == Some(term) {
+                    m.entries = m.entries.split_off(&(idx + 1));
+                    m.offset = idx + 1;
+                }
+            }
+            Op::StableSnapTo(idx) => {
+                u.stable_snap_to(idx);
+                if m.snapshot.map(|(index, _)| index) == Some(idx) {
+                    m.snapshot = None;
+                }
+            }
+            Op::Restore(index, term) => {
+                u.restore(new_snapshot(index, term));
+                m.entries.clear();
+                m.offset = index + 1;
+                m.snapshot = Some((index, term));
+            }
+        }
+    }
+
+    fn check_invariants(u: &Unstable, m: &Model) {
+        assert_eq!(u.offset, m.offset);
+        for (i, e) in u.entries.iter().enumerate() {
+            assert_eq!(e.index, u.offset + i as u64, "entries must be continuous");
+        }
+        assert_eq!(u.maybe_last_index(), m.last_index());
+        assert_eq!(
+            u.maybe_first_index(),
+            m.snapshot.map(|(index, _)| index + 1)
+        );
+        let hi = u.offset + u.entries.len() as u64 + 2;
+        for idx in u.offset.saturating_sub(3)..hi {
+            assert_eq!(u.maybe_term(idx), m.term(idx), "maybe_term({})", idx);
+        }
+    }
+
+    #[test]
+    fn test_unstable_model() {
+        run_model_test(
+            || {
+                let u = Unstable::new(1, crate::default_logger());
+                let m = Model {
+                    offset: 1,
+                    ..Default::default()
+                };
+                (u, m)
+            },
+            |rng, (_, m)| random_op(rng, m),
+            |(u, m), op| {
+                apply_op(u, m, op);
+                check_invariants(u, m);
+            },
+        );
+    }
+
+    #[test]
+    fn test_default_logger() {
+        let logger = crate::default_logger();
+        error!(logger, "开始记录数据");
     }
 }
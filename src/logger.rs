@@ -0,0 +1,222 @@
+//! 日志记录器的构造。
+//!
+//! 库以及二进制程序都通过 `LoggerBuilder` 创建根记录器，可以选择终端或者 JSON 格式、
+//! 同步或者异步输出、日志级别，以及附加在每条日志上的节点上下文。
+
+use std::io;
+use std::sync::Mutex;
+
+use slog::{Drain, Level, LevelFilter, Logger, Never};
+
+/// 日志的输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// 便于阅读的终端格式
+    Terminal,
+    /// 每行一个 JSON 对象，便于日志系统收集
+    Json,
+}
+
+type BoxDrain = Box<dyn Drain<Ok = (), Err = Never> + Send>;
+
+/// 根日志记录器的构造器
+pub struct LoggerBuilder {
+    format: Format,
+    level: Level,
+    env_filter: bool,
+    async_drain: bool,
+    writer: Option<Box<dyn io::Write + Send>>,
+    values: Vec<(&'static str, String)>,
+}
+
+impl Default for LoggerBuilder {
+    fn default() -> LoggerBuilder {
+        LoggerBuilder {
+            format: Format::Terminal,
+            level: Level::Info,
+            env_filter: false,
+            async_drain: false,
+            writer: None,
+            values: vec![],
+        }
+    }
+}
+
+impl LoggerBuilder {
+    /// 创建一个构造器，默认同步输出 `Info` 级别以上的终端格式日志到标准错误
+    pub fn new() -> LoggerBuilder {
+        LoggerBuilder::default()
+    }
+
+    /// 设置输出格式
+    pub fn format(mut self, format: Format) -> LoggerBuilder {
+        self.format = format;
+        self
+    }
+
+    /// 只输出不低于 `level` 的日志
+    pub fn level(mut self, level: Level) -> LoggerBuilder {
+        self.level = level;
+        self
+    }
+
+    /// 是否额外使用 `RUST_LOG` 环境变量过滤日志
+    pub fn env_filter(mut self, enable: bool) -> LoggerBuilder {
+        self.env_filter = enable;
+        self
+    }
+
+    /// 是否在后台线程中输出日志，避免写日志阻塞 Raft 线程
+    pub fn async_drain(mut self, enable: bool) -> LoggerBuilder {
+        self.async_drain = enable;
+        self
+    }
+
+    /// 输出到 `w` 而不是标准错误
+    pub fn writer<W: io::Write + Send + 'static>(mut self, w: W) -> LoggerBuilder {
+        self.writer = Some(Box::new(w));
+        self
+    }
+
+    /// 每条日志都带上给定的键值。
+    /// 不需要在这里设置节点ID，`Raft` 以及 `Transport` 会在自己的日志中添加 `raft_id`
+    pub fn value<V: Into<String>>(mut self, key: &'static str, value: V) -> LoggerBuilder {
+        self.values.push((key, value.into()));
+        self
+    }
+
+    /// 创建根日志记录器
+    pub fn build(self) -> Logger {
+        let drain: BoxDrain = match (self.format, self.writer) {
+            (Format::Terminal, None) => {
+                let decorator = slog_term::TermDecorator::new().build();
+                Box::new(
+                    slog_term::CompactFormat::new(decorator)
+                        .build()
+                        .ignore_res(),
+                )
+            }
+            (Format::Terminal, Some(w)) => {
+                let decorator = slog_term::PlainDecorator::new(w);
+                Box::new(
+                    slog_term::CompactFormat::new(decorator)
+                        .build()
+                        .ignore_res(),
+                )
+            }
+            (Format::Json, w) => {
+                let w = w.unwrap_or_else(|| Box::new(io::stderr()));
+                Box::new(
+                    slog_json::Json::new(w)
+                        .add_default_keys()
+                        .build()
+                        .ignore_res(),
+                )
+            }
+        };
+        let drain = LevelFilter::new(drain, self.level).ignore_res();
+        let drain: BoxDrain = if self.env_filter {
+            Box::new(slog_envlogger::new(drain).ignore_res())
+        } else {
+            Box::new(drain)
+        };
+        let mut logger = if self.async_drain {
+            Logger::root(slog_async::Async::new(drain).build().fuse(), o!())
+        } else {
+            Logger::root(Mutex::new(drain).fuse(), o!())
+        };
+        for (k, v) in self.values {
+            logger = logger.new(o!(k => v));
+        }
+        logger
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn lines(&self) -> Vec<String> {
+            let data = self.0.lock().unwrap();
+            String::from_utf8_lossy(&data)
+                .lines()
+                .map(|l| l.to_owned())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_json_logger() {
+        let buf = Buffer::default();
+        let logger = LoggerBuilder::new()
+            .format(Format::Json)
+            .level(Level::Info)
+            .writer(buf.clone())
+            .value("cluster", "c1")
+            .build();
+        debug!(logger, "filtered");
+        info!(logger, "commit index advanced"; "prev_commit" => 1, "commit" => 2);
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        for s in &[
+            "\"msg\":\"commit index advanced\"",
+            "\"cluster\":\"c1\"",
+            "\"prev_commit\":1",
+            "\"commit\":2",
+        ] {
+            assert!(lines[0].contains(s), "{} not in {}", s, lines[0]);
+        }
+
+        // Raft 自己添加 raft_id，每条日志中只出现一次
+        let store = crate::storage::MemStorage::new();
+        crate::raft::Raft::new(&crate::config::Config::new(3), store, &logger).unwrap();
+        let lines = buf.lines();
+        let last = lines.last().unwrap();
+        assert!(last.contains("\"msg\":\"new raft\""), "{}", last);
+        assert_eq!(last.matches("\"raft_id\":3").count(), 1, "{}", last);
+    }
+
+    #[test]
+    fn test_async_logger() {
+        let buf = Buffer::default();
+        let logger = LoggerBuilder::new()
+            .async_drain(true)
+            .writer(buf.clone())
+            .value("cluster", "mq")
+            .build();
+        warn!(logger, "async"; "term" => 5);
+        // 丢弃所有的记录器之后异步线程会输出剩余的日志
+        drop(logger);
+        // 终端格式会把上下文单独输出在一行
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].contains("cluster: mq"), "{}", lines[0]);
+        assert!(lines[1].contains("WARN async, term: 5"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_default_logger_unnamed_thread() {
+        thread::spawn(|| {
+            let logger = crate::default_logger();
+            error!(logger, "logging from an unnamed thread");
+        })
+        .join()
+        .unwrap();
+    }
+}
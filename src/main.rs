@@ -1,12 +1,21 @@
+#[macro_use]
 extern crate slog;
-extern crate slog_async;
-extern crate slog_term;
 
-use slog::*;
+use hello::logger::{Format, LoggerBuilder};
 
 fn main() {
-    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
-    let logger = Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!());
+    // 传入 --json 时输出 JSON 格式的日志，便于日志系统收集
+    let format = if std::env::args().any(|a| a == "--json") {
+        Format::Json
+    } else {
+        Format::Terminal
+    };
+    let logger = LoggerBuilder::new()
+        .format(format)
+        // 设置了 RUST_LOG 时按照环境变量过滤，否则输出 Info 以上的日志
+        .env_filter(std::env::var_os("RUST_LOG").is_some())
+        .async_drain(true)
+        .build();
 
     info!(logger, "Logging ready!");
 
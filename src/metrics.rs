@@ -0,0 +1,449 @@
+//! Raft 运行指标。
+//!
+//! `Metrics` 由 Raft 以及传输模块在关键路径上调用，默认使用不做任何事情的 `NoopMetrics`。
+//! `InMemoryMetrics` 在内存中累计计数器与直方图，可以通过 `prometheus_text`
+//! 导出为 Prometheus 的文本格式，由应用层的 HTTP 服务暴露给监控系统。
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use protobuf::ProtobufEnum;
+
+use crate::protos::eraftpb::MessageType;
+
+/// 提交延迟直方图默认的桶上界，单位为秒
+pub const COMMIT_LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0,
+];
+/// 追加消息中条目数量直方图默认的桶上界
+pub const APPEND_BATCH_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+
+/// Raft 运行指标的收集接口，所有方法默认不做任何事情
+pub trait Metrics: Send + Sync {
+    /// 发起了一次选举
+    fn election_started(&self) {}
+    /// 任期发生了变化
+    fn term_changed(&self, _term: u64) {}
+    /// 提议被丢弃，例如没有领导者或者正在转移领导权
+    fn proposal_dropped(&self) {}
+    /// 发送了一条消息
+    fn message_sent(&self, _t: MessageType) {}
+    /// 收到了一条消息
+    fn message_received(&self, _t: MessageType) {}
+    /// 发送了一个快照
+    fn snapshot_sent(&self) {}
+    /// 从提议到提交经过的时间
+    fn observe_commit_latency(&self, _latency: Duration) {}
+    /// 一条追加消息从发送队列中取出时携带的条目数量，包括合并进来的条目
+    fn observe_append_batch(&self, _entries: usize) {}
+}
+
+/// 不收集任何指标
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// 固定桶的直方图
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct HistogramState {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    /// 使用递增的桶上界创建直方图
+    ///
+    /// # Panics
+    ///
+    /// 如果桶上界不是严格递增的
+    pub fn new(bounds: &[f64]) -> Histogram {
+        assert!(
+            bounds.windows(2).all(|w| w[0] < w[1]),
+            "histogram bounds must be increasing: {:?}",
+            bounds
+        );
+        Histogram {
+            bounds: bounds.to_vec(),
+            state: Mutex::new(HistogramState {
+                buckets: vec![0; bounds.len()],
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// 记录一个观测值
+    pub fn observe(&self, v: f64) {
+        let mut s = self.state.lock().unwrap();
+        if let Some(i) = self.bounds.iter().position(|b| v <= *b) {
+            s.buckets[i] += 1;
+        }
+        s.sum += v;
+        s.count += 1;
+    }
+
+    /// 观测值的数量
+    pub fn count(&self) -> u64 {
+        self.state.lock().unwrap().count
+    }
+
+    /// 观测值的总和
+    pub fn sum(&self) -> f64 {
+        self.state.lock().unwrap().sum
+    }
+
+    /// 每个桶的上界以及不超过该上界的观测值数量（累计值）
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        self.cumulative(&self.state.lock().unwrap())
+    }
+
+    fn cumulative(&self, s: &HistogramState) -> Vec<(f64, u64)> {
+        let mut acc = 0;
+        self.bounds
+            .iter()
+            .zip(s.buckets.iter())
+            .map(|(b, n)| {
+                acc += n;
+                (*b, acc)
+            })
+            .collect()
+    }
+
+    fn write_prometheus(&self, out: &mut String, name: &str, help: &str) {
+        let s = self.state.lock().unwrap();
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (b, n) in self.cumulative(&s) {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, b, n).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, s.count).unwrap();
+        writeln!(out, "{}_sum {}", name, s.sum).unwrap();
+        writeln!(out, "{}_count {}", name, s.count).unwrap();
+    }
+}
+
+/// 在内存中累计的指标
+#[derive(Debug)]
+pub struct InMemoryMetrics {
+    elections: AtomicU64,
+    term_changes: AtomicU64,
+    proposals_dropped: AtomicU64,
+    snapshots_sent: AtomicU64,
+    sent: Mutex<HashMap<MessageType, u64>>,
+    received: Mutex<HashMap<MessageType, u64>>,
+    /// 提交延迟，单位为秒
+    pub commit_latency: Histogram,
+    /// 追加消息中的条目数量
+    pub append_batch: Histogram,
+}
+
+impl Default for InMemoryMetrics {
+    fn default() -> InMemoryMetrics {
+        InMemoryMetrics {
+            elections: AtomicU64::new(0),
+            term_changes: AtomicU64::new(0),
+            proposals_dropped: AtomicU64::new(0),
+            snapshots_sent: AtomicU64::new(0),
+            sent: Mutex::new(HashMap::new()),
+            received: Mutex::new(HashMap::new()),
+            commit_latency: Histogram::new(COMMIT_LATENCY_BUCKETS),
+            append_batch: Histogram::new(APPEND_BATCH_BUCKETS),
+        }
+    }
+}
+
+impl InMemoryMetrics {
+    /// 创建一个所有指标都为 0 的实例
+    pub fn new() -> InMemoryMetrics {
+        InMemoryMetrics::default()
+    }
+
+    /// 发起选举的次数
+    pub fn elections(&self) -> u64 {
+        self.elections.load(Ordering::Relaxed)
+    }
+
+    /// 任期变化的次数
+    pub fn term_changes(&self) -> u64 {
+        self.term_changes.load(Ordering::Relaxed)
+    }
+
+    /// 被丢弃的提议数量
+    pub fn proposals_dropped(&self) -> u64 {
+        self.proposals_dropped.load(Ordering::Relaxed)
+    }
+
+    /// 发送快照的次数
+    pub fn snapshots_sent(&self) -> u64 {
+        self.snapshots_sent.load(Ordering::Relaxed)
+    }
+
+    /// 发送的某种类型的消息数量
+    pub fn messages_sent(&self, t: MessageType) -> u64 {
+        self.sent.lock().unwrap().get(&t).cloned().unwrap_or(0)
+    }
+
+    /// 收到的某种类型的消息数量
+    pub fn messages_received(&self, t: MessageType) -> u64 {
+        self.received.lock().unwrap().get(&t).cloned().unwrap_or(0)
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn election_started(&self) {
+        self.elections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn term_changed(&self, _term: u64) {
+        self.term_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn proposal_dropped(&self) {
+        self.proposals_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_sent(&self, t: MessageType) {
+        *self.sent.lock().unwrap().entry(t).or_insert(0) += 1;
+    }
+
+    fn message_received(&self, t: MessageType) {
+        *self.received.lock().unwrap().entry(t).or_insert(0) += 1;
+    }
+
+    fn snapshot_sent(&self) {
+        self.snapshots_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn observe_commit_latency(&self, latency: Duration) {
+        self.commit_latency.observe(latency.as_secs_f64());
+    }
+
+    fn observe_append_batch(&self, entries: usize) {
+        self.append_batch.observe(entries as f64);
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, v: u64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    writeln!(out, "{} {}", name, v).unwrap();
+}
+
+fn write_message_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    counts: &Mutex<HashMap<MessageType, u64>>,
+) {
+    let counts = counts.lock().unwrap();
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    // 按照消息类型的定义顺序输出，保证结果稳定
+    for t in MessageType::values() {
+        if let Some(n) = counts.get(t) {
+            writeln!(out, "{}{{type=\"{:?}\"}} {}", name, t, n).unwrap();
+        }
+    }
+}
+
+/// 把指标导出为 Prometheus 的文本格式
+///
+/// 选举、任期变化、丢弃的提议以及提交延迟需要 Raft 的选举与提议逻辑来更新，
+/// 在这些逻辑实现之前 `raft_elections_total`、`raft_term_changes_total`、
+/// `raft_proposals_dropped_total` 以及 `raft_commit_latency_seconds` 始终没有数据，
+/// 它们的 0 并不表示没有发生过对应的事件
+pub fn prometheus_text(m: &InMemoryMetrics) -> String {
+    let mut out = String::new();
+    write_counter(
+        &mut out,
+        "raft_elections_total",
+        "Number of elections started.",
+        m.elections(),
+    );
+    write_counter(
+        &mut out,
+        "raft_term_changes_total",
+        "Number of term changes.",
+        m.term_changes(),
+    );
+    write_counter(
+        &mut out,
+        "raft_proposals_dropped_total",
+        "Number of dropped proposals.",
+        m.proposals_dropped(),
+    );
+    write_counter(
+        &mut out,
+        "raft_snapshots_sent_total",
+        "Number of snapshots sent.",
+        m.snapshots_sent(),
+    );
+    write_message_counter(
+        &mut out,
+        "raft_messages_sent_total",
+        "Number of messages sent by type.",
+        &m.sent,
+    );
+    write_message_counter(
+        &mut out,
+        "raft_messages_received_total",
+        "Number of messages received by type.",
+        &m.received,
+    );
+    m.commit_latency.write_prometheus(
+        &mut out,
+        "raft_commit_latency_seconds",
+        "Latency from proposal to commit.",
+    );
+    m.append_batch.write_prometheus(
+        &mut out,
+        "raft_append_batch_entries",
+        "Number of entries carried by append messages.",
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::protos::eraftpb::{Entry, Message};
+    use crate::raft::Raft;
+    use crate::storage::MemStorage;
+    use crate::util::NO_LIMIT;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::new(&[1.0, 2.0, 4.0]);
+        for v in &[0.5, 1.0, 3.0, 10.0] {
+            h.observe(*v);
+        }
+        assert_eq!(h.count(), 4);
+        assert_eq!(h.sum(), 14.5);
+        assert_eq!(h.buckets(), vec![(1.0, 2), (2.0, 2), (4.0, 3)]);
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let m = InMemoryMetrics::new();
+        m.election_started();
+        m.term_changed(2);
+        m.message_sent(MessageType::MsgHeartbeat);
+        m.message_sent(MessageType::MsgAppend);
+        m.message_sent(MessageType::MsgAppend);
+        m.message_received(MessageType::MsgAppendResponse);
+        m.observe_append_batch(3);
+        m.observe_commit_latency(Duration::from_millis(3));
+
+        let text = prometheus_text(&m);
+        for line in &[
+            "# TYPE raft_elections_total counter",
+            "raft_elections_total 1",
+            "raft_term_changes_total 1",
+            "raft_proposals_dropped_total 0",
+            "raft_messages_sent_total{type=\"MsgAppend\"} 2",
+            "raft_messages_sent_total{type=\"MsgHeartbeat\"} 1",
+            "raft_messages_received_total{type=\"MsgAppendResponse\"} 1",
+            "# TYPE raft_append_batch_entries histogram",
+            "raft_append_batch_entries_bucket{le=\"2\"} 0",
+            "raft_append_batch_entries_bucket{le=\"4\"} 1",
+            "raft_append_batch_entries_bucket{le=\"+Inf\"} 1",
+            "raft_append_batch_entries_sum 3",
+            "raft_commit_latency_seconds_bucket{le=\"0.002\"} 0",
+            "raft_commit_latency_seconds_bucket{le=\"0.005\"} 1",
+            "raft_commit_latency_seconds_count 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == *line),
+                "{} not in\n{}",
+                line,
+                text
+            );
+        }
+        // 消息类型按照定义顺序输出
+        let append = text.find("type=\"MsgAppend\"").unwrap();
+        let heartbeat = text.find("type=\"MsgHeartbeat\"").unwrap();
+        assert!(append < heartbeat);
+    }
+
+    fn new_append(to: u64, index: u64, ents: std::ops::Range<u64>) -> Message {
+        let mut m = Message {
+            to,
+            index,
+            ..Default::default()
+        };
+        m.set_msg_type(MessageType::MsgAppend);
+        for i in ents {
+            m.mut_entries().push(Entry {
+                index: i,
+                ..Default::default()
+            });
+        }
+        m
+    }
+
+    #[test]
+    fn test_raft_send_metrics() {
+        let l = crate::default_logger();
+        let mut r = Raft::new(&Config::new(1), MemStorage::new(), &l).unwrap();
+        let m = Arc::new(InMemoryMetrics::new());
+        r.set_metrics(m.clone());
+
+        let mut msg = Message::default();
+        msg.set_msg_type(MessageType::MsgAppend);
+        msg.set_entries(vec![Entry::default(); 3].into());
+        r.send(msg);
+        let mut msg = Message::default();
+        msg.set_msg_type(MessageType::MsgSnapshot);
+        r.send(msg);
+
+        assert_eq!(m.messages_sent(MessageType::MsgAppend), 1);
+        assert_eq!(m.messages_sent(MessageType::MsgSnapshot), 1);
+        assert_eq!(m.messages_sent(MessageType::MsgHeartbeat), 0);
+        assert_eq!(m.snapshots_sent(), 1);
+        assert_eq!(m.append_batch.count(), 0);
+        assert_eq!(r.take_msgs().len(), 2);
+        assert!(r.msgs.is_empty());
+        assert_eq!(m.append_batch.count(), 1);
+        assert_eq!(m.append_batch.sum(), 3.0);
+
+        // 开启 batch_append 时只统计真正进入发送队列的消息
+        let c = Config {
+            batch_append: true,
+            max_size_per_msg: NO_LIMIT,
+            ..Config::new(1)
+        };
+        let mut r = Raft::new(&c, MemStorage::new(), &l).unwrap();
+        let m = Arc::new(InMemoryMetrics::new());
+        r.set_metrics(m.clone());
+
+        r.send(new_append(2, 0, 1..4));
+        // 合并到上一条追加消息中，不会单独发送
+        r.send(new_append(2, 3, 4..6));
+        let mut hb = Message {
+            to: 2,
+            ..Default::default()
+        };
+        hb.set_msg_type(MessageType::MsgHeartbeat);
+        r.send(hb);
+        r.send(new_append(2, 5, 6..7));
+
+        assert_eq!(m.messages_sent(MessageType::MsgAppend), 2);
+        assert_eq!(m.messages_sent(MessageType::MsgHeartbeat), 1);
+        // 合并进来的条目同样被统计
+        assert_eq!(r.take_msgs().len(), 3);
+        assert_eq!(m.append_batch.count(), 2);
+        assert_eq!(m.append_batch.sum(), 6.0);
+    }
+}
@@ -0,0 +1,576 @@
+//! pingchuan-mq 使用的复制消息队列状态机。
+//!
+//! 每个主题包含若干分区，生产消息以及消费组提交位点都作为日志条目提交，
+//! 所有副本按照相同的顺序应用这些条目，得到相同的队列状态。
+//! 幂等生产者通过 `(producer_id, sequence)` 在应用时去重，重试的消息不会被写入两次。
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::errors::{Error, Result};
+use crate::protos::eraftpb::{Entry, EntryType};
+
+/// 队列状态机的命令，编码后作为日志条目的数据
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// 创建主题
+    CreateTopic {
+        /// 主题名
+        topic: String,
+        /// 分区数量
+        partitions: u32,
+    },
+    /// 生产一条消息，`producer_id` 为 0 时不做幂等去重
+    Produce {
+        /// 主题名
+        topic: String,
+        /// 分区
+        partition: u32,
+        /// 生产者ID
+        producer_id: u64,
+        /// 生产者内单调递增的序号
+        sequence: u64,
+        /// 消息内容
+        payload: Vec<u8>,
+    },
+    /// 提交消费组的位点
+    CommitOffset {
+        /// 消费组
+        group: String,
+        /// 主题名
+        topic: String,
+        /// 分区
+        partition: u32,
+        /// 下一条需要消费的消息位置
+        offset: u64,
+    },
+}
+
+const CREATE_TOPIC: u8 = 1;
+const PRODUCE: u8 = 2;
+const COMMIT_OFFSET: u8 = 3;
+
+/// 单个主题允许的最大分区数量
+pub const MAX_PARTITIONS: u32 = 4096;
+
+impl Command {
+    /// 将命令编码为日志条目的数据
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Command::CreateTopic { topic, partitions } => {
+                buf.push(CREATE_TOPIC);
+                put_bytes(&mut buf, topic.as_bytes());
+                buf.extend_from_slice(&partitions.to_be_bytes());
+            }
+            Command::Produce {
+                topic,
+                partition,
+                producer_id,
+                sequence,
+                payload,
+            } => {
+                buf.push(PRODUCE);
+                put_bytes(&mut buf, topic.as_bytes());
+                buf.extend_from_slice(&partition.to_be_bytes());
+                buf.extend_from_slice(&producer_id.to_be_bytes());
+                buf.extend_from_slice(&sequence.to_be_bytes());
+                put_bytes(&mut buf, payload);
+            }
+            Command::CommitOffset {
+                group,
+                topic,
+                partition,
+                offset,
+            } => {
+                buf.push(COMMIT_OFFSET);
+                put_bytes(&mut buf, group.as_bytes());
+                put_bytes(&mut buf, topic.as_bytes());
+                buf.extend_from_slice(&partition.to_be_bytes());
+                buf.extend_from_slice(&offset.to_be_bytes());
+            }
+        }
+        buf
+    }
+
+    /// 从日志条目的数据中解码命令
+    pub fn decode(data: &[u8]) -> Result<Command> {
+        let mut r = Reader { data };
+        let cmd = match r.u8()? {
+            CREATE_TOPIC => Command::CreateTopic {
+                topic: r.string()?,
+                partitions: r.u32()?,
+            },
+            PRODUCE => Command::Produce {
+                topic: r.string()?,
+                partition: r.u32()?,
+                producer_id: r.u64()?,
+                sequence: r.u64()?,
+                payload: r.bytes()?.to_vec(),
+            },
+            COMMIT_OFFSET => Command::CommitOffset {
+                group: r.string()?,
+                topic: r.string()?,
+                partition: r.u32()?,
+                offset: r.u64()?,
+            },
+            t => return Err(invalid_data(format!("unknown command type {}", t))),
+        };
+        if !r.data.is_empty() {
+            return Err(invalid_data(format!(
+                "{} trailing bytes after command",
+                r.data.len()
+            )));
+        }
+        Ok(cmd)
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, b: &[u8]) {
+    buf.extend_from_slice(&(b.len() as u32).to_be_bytes());
+    buf.extend_from_slice(b);
+}
+
+fn invalid_data(desc: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, desc))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(invalid_data(format!(
+                "command truncated, need {} bytes but {} left",
+                n,
+                self.data.len()
+            )));
+        }
+        let (l, r) = self.data.split_at(n);
+        self.data = r;
+        Ok(l)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(b))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|e| invalid_data(e.to_string()))
+    }
+}
+
+/// 分区中的一条消息
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// 消息在分区中的位置
+    pub offset: u64,
+    /// 写入该消息的日志条目下标
+    pub index: u64,
+    /// 生产者ID
+    pub producer_id: u64,
+    /// 生产者序号
+    pub sequence: u64,
+    /// 消息内容
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Partition {
+    records: Vec<Record>,
+    /// 每个生产者最后写入的 (sequence, offset)
+    producers: HashMap<u64, (u64, u64)>,
+}
+
+/// 应用一条日志条目的结果，拒绝的命令同样是确定性的，所有副本都会得到相同的结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Applied {
+    /// 空条目或者非普通条目，没有修改队列状态
+    Noop,
+    /// 主题已经创建
+    TopicCreated,
+    /// 消息已经写入指定位置
+    Produced(u64),
+    /// 生产者重试了最后一条消息，返回第一次写入时的位置
+    Duplicate(u64),
+    /// 位点已经提交
+    OffsetCommitted,
+    /// 命令被拒绝
+    Rejected(String),
+}
+
+/// 复制消息队列的状态机
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageQueue {
+    topics: BTreeMap<String, Vec<Partition>>,
+    offsets: BTreeMap<(String, String, u32), u64>,
+    applied_index: u64,
+}
+
+impl MessageQueue {
+    /// 创建一个空的队列
+    pub fn new() -> MessageQueue {
+        MessageQueue::default()
+    }
+
+    /// 最后一次应用的日志下标
+    pub fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    /// 应用一条已经提交的日志条目
+    ///
+    /// 重复应用同一个下标时返回错误。无法解码的条目同样会推进应用下标并以
+    /// `Applied::Rejected` 返回，否则副本会一直卡在这条日志上
+    pub fn apply(&mut self, e: &Entry) -> Result<Applied> {
+        if e.index <= self.applied_index {
+            return Err(Error::ViolatesContract(format!(
+                "entry {} has already been applied, applied index {}",
+                e.index, self.applied_index
+            )));
+        }
+        if e.get_entry_type() != EntryType::EntryNormal || e.data.is_empty() {
+            self.applied_index = e.index;
+            return Ok(Applied::Noop);
+        }
+        self.applied_index = e.index;
+        match Command::decode(&e.data) {
+            Ok(cmd) => Ok(self.apply_command(e.index, cmd)),
+            Err(err) => Ok(Applied::Rejected(format!(
+                "decode entry {} failed: {}",
+                e.index, err
+            ))),
+        }
+    }
+
+    fn apply_command(&mut self, index: u64, cmd: Command) -> Applied {
+        match cmd {
+            Command::CreateTopic { topic, partitions } => {
+                if partitions == 0 {
+                    return Applied::Rejected("partitions must be greater than 0".to_owned());
+                }
+                if partitions > MAX_PARTITIONS {
+                    return Applied::Rejected(format!(
+                        "partitions {} exceeds {}",
+                        partitions, MAX_PARTITIONS
+                    ));
+                }
+                if self.topics.contains_key(&topic) {
+                    return Applied::Rejected(format!("topic {} already exists", topic));
+                }
+                self.topics
+                    .insert(topic, vec![Partition::default(); partitions as usize]);
+                Applied::TopicCreated
+            }
+            Command::Produce {
+                topic,
+                partition,
+                producer_id,
+                sequence,
+                payload,
+            } => {
+                let p = match self.partition_mut(&topic, partition) {
+                    Ok(p) => p,
+                    Err(reason) => return Applied::Rejected(reason),
+                };
+                if producer_id != 0 {
+                    // 只记录了每个生产者最后一条消息的位置，更早的序号无法给出写入位置
+                    if let Some(&(last_seq, offset)) = p.producers.get(&producer_id) {
+                        if sequence == last_seq {
+                            return Applied::Duplicate(offset);
+                        }
+                        if sequence < last_seq {
+                            return Applied::Rejected(format!(
+                                "stale sequence {} of producer {}, last sequence {}",
+                                sequence, producer_id, last_seq
+                            ));
+                        }
+                    }
+                }
+                let offset = p.records.len() as u64;
+                p.records.push(Record {
+                    offset,
+                    index,
+                    producer_id,
+                    sequence,
+                    payload,
+                });
+                if producer_id != 0 {
+                    p.producers.insert(producer_id, (sequence, offset));
+                }
+                Applied::Produced(offset)
+            }
+            Command::CommitOffset {
+                group,
+                topic,
+                partition,
+                offset,
+            } => {
+                let end = match self.partition_mut(&topic, partition) {
+                    Ok(p) => p.records.len() as u64,
+                    Err(reason) => return Applied::Rejected(reason),
+                };
+                if offset > end {
+                    return Applied::Rejected(format!(
+                        "offset {} is beyond the end {} of {}-{}",
+                        offset, end, topic, partition
+                    ));
+                }
+                self.offsets.insert((group, topic, partition), offset);
+                Applied::OffsetCommitted
+            }
+        }
+    }
+
+    fn partition_mut(
+        &mut self,
+        topic: &str,
+        partition: u32,
+    ) -> ::std::result::Result<&mut Partition, String> {
+        match self.topics.get_mut(topic) {
+            None => Err(format!("topic {} does not exist", topic)),
+            Some(ps) => ps
+                .get_mut(partition as usize)
+                .ok_or_else(|| format!("partition {}-{} does not exist", topic, partition)),
+        }
+    }
+
+    /// 主题的分区数量
+    pub fn partitions(&self, topic: &str) -> Option<u32> {
+        self.topics.get(topic).map(|ps| ps.len() as u32)
+    }
+
+    /// 从指定位置开始读取最多 `max` 条消息
+    pub fn fetch(&self, topic: &str, partition: u32, offset: u64, max: usize) -> &[Record] {
+        let records = match self
+            .topics
+            .get(topic)
+            .and_then(|ps| ps.get(partition as usize))
+        {
+            Some(p) => &p.records,
+            None => return &[],
+        };
+        let start = (offset as usize).min(records.len());
+        let end = start.saturating_add(max).min(records.len());
+        &records[start..end]
+    }
+
+    /// 消费组已经提交的位点
+    pub fn committed_offset(&self, group: &str, topic: &str, partition: u32) -> Option<u64> {
+        self.offsets
+            .get(&(group.to_owned(), topic.to_owned(), partition))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_entry(index: u64, cmd: &Command) -> Entry {
+        Entry {
+            index,
+            term: 1,
+            data: cmd.encode(),
+            ..Default::default()
+        }
+    }
+
+    fn produce(
+        topic: &str,
+        partition: u32,
+        producer_id: u64,
+        sequence: u64,
+        payload: &[u8],
+    ) -> Command {
+        Command::Produce {
+            topic: topic.to_owned(),
+            partition,
+            producer_id,
+            sequence,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_command_codec() {
+        let cmds = vec![
+            Command::CreateTopic {
+                topic: "orders".to_owned(),
+                partitions: 3,
+            },
+            produce("orders", 2, 7, 1, b"hello"),
+            Command::CommitOffset {
+                group: "billing".to_owned(),
+                topic: "orders".to_owned(),
+                partition: 2,
+                offset: 1,
+            },
+        ];
+        for cmd in cmds {
+            let data = cmd.encode();
+            assert_eq!(Command::decode(&data).unwrap(), cmd);
+            assert!(Command::decode(&data[..data.len() - 1]).is_err());
+        }
+        assert!(Command::decode(&[]).is_err());
+        assert!(Command::decode(&[9]).is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut mq = MessageQueue::new();
+        let cmds = vec![
+            (
+                produce("orders", 0, 0, 0, b"a"),
+                Applied::Rejected("topic orders does not exist".to_owned()),
+            ),
+            (
+                Command::CreateTopic {
+                    topic: "orders".to_owned(),
+                    partitions: 2,
+                },
+                Applied::TopicCreated,
+            ),
+            (
+                Command::CreateTopic {
+                    topic: "huge".to_owned(),
+                    partitions: MAX_PARTITIONS + 1,
+                },
+                Applied::Rejected(format!(
+                    "partitions {} exceeds {}",
+                    MAX_PARTITIONS + 1,
+                    MAX_PARTITIONS
+                )),
+            ),
+            (produce("orders", 1, 7, 1, b"a"), Applied::Produced(0)),
+            (produce("orders", 1, 7, 2, b"b"), Applied::Produced(1)),
+            // 生产者重试
+            (produce("orders", 1, 7, 2, b"b"), Applied::Duplicate(1)),
+            // 重试更早的消息，不能返回最后一条消息的位置
+            (
+                produce("orders", 1, 7, 1, b"a"),
+                Applied::Rejected("stale sequence 1 of producer 7, last sequence 2".to_owned()),
+            ),
+            (produce("orders", 1, 0, 0, b"c"), Applied::Produced(2)),
+            (produce("orders", 1, 0, 0, b"c"), Applied::Produced(3)),
+            (
+                produce("orders", 2, 7, 3, b"d"),
+                Applied::Rejected("partition orders-2 does not exist".to_owned()),
+            ),
+            (
+                Command::CommitOffset {
+                    group: "billing".to_owned(),
+                    topic: "orders".to_owned(),
+                    partition: 1,
+                    offset: 2,
+                },
+                Applied::OffsetCommitted,
+            ),
+        ];
+        for (i, (cmd, wapplied)) in cmds.iter().enumerate() {
+            let idx = i as u64 + 1;
+            assert_eq!(mq.apply(&new_entry(idx, cmd)).unwrap(), *wapplied, "#{}", i);
+            assert_eq!(mq.applied_index(), idx);
+        }
+
+        let payloads: Vec<&[u8]> = mq
+            .fetch("orders", 1, 1, 2)
+            .iter()
+            .map(|r| &r.payload[..])
+            .collect();
+        assert_eq!(payloads, vec![&b"b"[..], &b"c"[..]]);
+        assert_eq!(mq.committed_offset("billing", "orders", 1), Some(2));
+        assert_eq!(mq.committed_offset("billing", "orders", 0), None);
+
+        // 空条目是领导者上任时追加的，不修改状态
+        let idx = mq.applied_index() + 1;
+        assert_eq!(
+            mq.apply(&Entry {
+                index: idx,
+                ..Default::default()
+            })
+            .unwrap(),
+            Applied::Noop
+        );
+        assert!(mq
+            .apply(&Entry {
+                index: idx,
+                ..Default::default()
+            })
+            .is_err());
+
+        // 无法解码的条目被拒绝，但是应用下标仍然前进
+        let before = mq.clone();
+        let idx = idx + 1;
+        match mq.apply(&Entry {
+            index: idx,
+            data: vec![9],
+            ..Default::default()
+        }) {
+            Ok(Applied::Rejected(_)) => {}
+            res => panic!("expect rejected, got {:?}", res),
+        }
+        assert_eq!(mq.applied_index(), idx);
+        assert_eq!(
+            mq.fetch("orders", 1, 0, usize::MAX),
+            before.fetch("orders", 1, 0, usize::MAX)
+        );
+        let cmd = produce("orders", 0, 0, 0, b"e");
+        assert_eq!(
+            mq.apply(&new_entry(idx + 1, &cmd)).unwrap(),
+            Applied::Produced(0)
+        );
+    }
+
+    /// 应用是确定性的，同一份包含重试消息的已提交日志应用多次得到相同的状态
+    #[test]
+    fn test_apply_deterministic() {
+        let mut log = vec![new_entry(
+            1,
+            &Command::CreateTopic {
+                topic: "events".to_owned(),
+                partitions: 4,
+            },
+        )];
+        for i in 0..100u64 {
+            let cmd = produce("events", (i % 4) as u32, i % 3 + 1, i / 3, &i.to_be_bytes());
+            log.push(new_entry(log.len() as u64 + 1, &cmd));
+            if i % 10 == 0 {
+                // 模拟生产者超时重试，同一条消息被提交了两次
+                log.push(new_entry(log.len() as u64 + 1, &cmd));
+            }
+        }
+
+        let mut replicas = vec![MessageQueue::new(); 3];
+        for r in replicas.iter_mut() {
+            for e in &log {
+                r.apply(e).unwrap();
+            }
+        }
+        assert_eq!(replicas[0], replicas[1]);
+        assert_eq!(replicas[1], replicas[2]);
+        let total: usize = (0..4)
+            .map(|p| replicas[0].fetch("events", p, 0, usize::MAX).len())
+            .sum();
+        assert_eq!(total, 100);
+    }
+}
@@ -0,0 +1,354 @@
+//! 用于集群测试的内存网络。
+//!
+//! `Network` 把多个节点通过内存队列连接起来，可以按照 `(from, to, MessageType)`
+//! 对消息进行丢弃、延迟、重复、乱序以及网络分区，不需要真正的网络连接。
+
+use std::collections::{BTreeMap, HashSet};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::errors::Result;
+use crate::protos::eraftpb::{Message, MessageType};
+
+/// 接入 `Network` 的节点需要实现的接口
+pub trait Interface {
+    /// 处理一条发给当前节点的消息
+    fn step(&mut self, m: Message) -> Result<()>;
+
+    /// 取出当前节点待发送的消息
+    fn read_messages(&mut self) -> Vec<Message>;
+}
+
+/// 匹配到消息后执行的动作
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// 丢弃消息
+    Drop,
+    /// 延迟指定轮数之后再投递
+    Delay(u64),
+    /// 投递两次
+    Duplicate,
+}
+
+/// 消息过滤规则，字段为 `None` 时匹配任意值
+#[derive(Debug, Clone)]
+pub struct Filter {
+    /// 发送方
+    pub from: Option<u64>,
+    /// 接收方
+    pub to: Option<u64>,
+    /// 消息类型
+    pub msg_type: Option<MessageType>,
+    /// 命中规则的概率，取值范围为 [0, 1]
+    pub probability: f64,
+    /// 命中后执行的动作
+    pub action: Action,
+}
+
+impl Filter {
+    /// 创建一个匹配所有消息的规则
+    pub fn new(action: Action) -> Filter {
+        Filter {
+            from: None,
+            to: None,
+            msg_type: None,
+            probability: 1.0,
+            action,
+        }
+    }
+
+    /// 只匹配指定发送方
+    pub fn from(mut self, from: u64) -> Filter {
+        self.from = Some(from);
+        self
+    }
+
+    /// 只匹配指定接收方
+    pub fn to(mut self, to: u64) -> Filter {
+        self.to = Some(to);
+        self
+    }
+
+    /// 只匹配指定类型的消息
+    pub fn msg_type(mut self, t: MessageType) -> Filter {
+        self.msg_type = Some(t);
+        self
+    }
+
+    /// 按照概率命中规则
+    pub fn probability(mut self, p: f64) -> Filter {
+        self.probability = p;
+        self
+    }
+
+    // Option::is_none_or 需要 Rust 1.82，这里保持对旧编译器的兼容
+    #[allow(clippy::unnecessary_map_or)]
+    fn matches(&self, m: &Message) -> bool {
+        self.from.map_or(true, |from| from == m.from)
+            && self.to.map_or(true, |to| to == m.to)
+            && self.msg_type.map_or(true, |t| t == m.get_msg_type())
+    }
+}
+
+/// 连接多个节点的内存网络，所有随机行为都来源于创建时给定的种子
+pub struct Network<P: Interface> {
+    /// 网络中的节点
+    pub peers: BTreeMap<u64, P>,
+    filters: Vec<Filter>,
+    cuts: HashSet<(u64, u64)>,
+    delayed: Vec<(u64, Message)>,
+    reorder: bool,
+    round: u64,
+    rng: StdRng,
+}
+
+impl<P: Interface> Network<P> {
+    /// 使用给定的节点以及随机种子创建网络
+    pub fn new(peers: Vec<(u64, P)>, seed: u64) -> Network<P> {
+        Network {
+            peers: peers.into_iter().collect(),
+            filters: vec![],
+            cuts: HashSet::new(),
+            delayed: vec![],
+            reorder: false,
+            round: 0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// 添加一条过滤规则，规则按照添加顺序匹配，只执行第一条命中的规则
+    pub fn add_filter(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    /// 打乱每一轮中投递消息的顺序
+    pub fn set_reorder(&mut self, reorder: bool) {
+        self.reorder = reorder;
+    }
+
+    /// 断开两个节点之间的双向连接
+    pub fn cut(&mut self, a: u64, b: u64) {
+        self.cuts.insert((a, b));
+        self.cuts.insert((b, a));
+    }
+
+    /// 断开节点与其他所有节点的连接
+    pub fn isolate(&mut self, id: u64) {
+        let ids: Vec<u64> = self.peers.keys().cloned().collect();
+        for other in ids {
+            if other != id {
+                self.cut(id, other);
+            }
+        }
+    }
+
+    /// 把网络分成互不连通的若干部分
+    pub fn partition(&mut self, groups: &[&[u64]]) {
+        for (i, a) in groups.iter().enumerate() {
+            for b in &groups[i + 1..] {
+                for &x in a.iter() {
+                    for &y in b.iter() {
+                        self.cut(x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 清除所有的过滤规则以及网络分区，已经延迟的消息仍会被投递
+    pub fn recover(&mut self) {
+        self.filters.clear();
+        self.cuts.clear();
+    }
+
+    /// 根据网络分区以及过滤规则处理待发送的消息
+    pub fn filter(&mut self, msgs: Vec<Message>) -> Vec<Message> {
+        let mut out = Vec::with_capacity(msgs.len());
+        for m in msgs {
+            if self.cuts.contains(&(m.from, m.to)) {
+                continue;
+            }
+            let mut action = None;
+            for f in &self.filters {
+                if f.matches(&m) && (f.probability >= 1.0 || self.rng.gen_bool(f.probability)) {
+                    action = Some(f.action);
+                    break;
+                }
+            }
+            match action {
+                None => out.push(m),
+                Some(Action::Drop) => {}
+                Some(Action::Delay(rounds)) => self.delayed.push((self.round + rounds, m)),
+                Some(Action::Duplicate) => {
+                    out.push(m.clone());
+                    out.push(m);
+                }
+            }
+        }
+        out
+    }
+
+    /// 投递消息，直到网络中没有待投递以及被延迟的消息为止
+    pub fn send(&mut self, msgs: Vec<Message>) {
+        let mut msgs = self.filter(msgs);
+        while !msgs.is_empty() || !self.delayed.is_empty() {
+            self.round += 1;
+            let round = self.round;
+            let (due, pending) = self
+                .delayed
+                .drain(..)
+                .partition::<Vec<_>, _>(|(r, _)| *r <= round);
+            self.delayed = pending;
+            msgs.extend(due.into_iter().map(|(_, m)| m));
+            if self.reorder {
+                msgs.shuffle(&mut self.rng);
+            }
+
+            let mut new_msgs = vec![];
+            for m in msgs.drain(..) {
+                if let Some(p) = self.peers.get_mut(&m.to) {
+                    // 测试网络中节点返回的错误与真实网络一样会被忽略
+                    let _ = p.step(m);
+                    new_msgs.extend(p.read_messages());
+                }
+            }
+            msgs = self.filter(new_msgs);
+        }
+    }
+
+    /// 取出所有节点待发送的消息并投递
+    pub fn flush(&mut self) {
+        let msgs: Vec<Message> = self
+            .peers
+            .values_mut()
+            .flat_map(|p| p.read_messages())
+            .collect();
+        self.send(msgs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 收到心跳后回复的测试节点
+    #[derive(Default)]
+    struct Echo {
+        id: u64,
+        received: Vec<Message>,
+        msgs: Vec<Message>,
+    }
+
+    impl Interface for Echo {
+        fn step(&mut self, m: Message) -> Result<()> {
+            if m.get_msg_type() == MessageType::MsgHeartbeat {
+                let mut resp = Message::default();
+                resp.set_msg_type(MessageType::MsgHeartbeatResponse);
+                resp.from = self.id;
+                resp.to = m.from;
+                self.msgs.push(resp);
+            }
+            self.received.push(m);
+            Ok(())
+        }
+
+        fn read_messages(&mut self) -> Vec<Message> {
+            self.msgs.drain(..).collect()
+        }
+    }
+
+    fn new_network(n: u64, seed: u64) -> Network<Echo> {
+        let peers = (1..=n)
+            .map(|id| {
+                (
+                    id,
+                    Echo {
+                        id,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        Network::new(peers, seed)
+    }
+
+    fn heartbeat(from: u64, to: u64) -> Message {
+        let mut m = Message::default();
+        m.set_msg_type(MessageType::MsgHeartbeat);
+        m.from = from;
+        m.to = to;
+        m
+    }
+
+    fn received(nw: &Network<Echo>, id: u64) -> usize {
+        nw.peers[&id].received.len()
+    }
+
+    #[test]
+    fn test_network_send() {
+        let mut nw = new_network(3, 0);
+        nw.send(vec![heartbeat(1, 2), heartbeat(1, 3)]);
+        assert_eq!(received(&nw, 1), 2);
+        assert_eq!(received(&nw, 2), 1);
+        assert_eq!(received(&nw, 3), 1);
+    }
+
+    #[test]
+    fn test_network_filter() {
+        let mut nw = new_network(3, 0);
+        nw.add_filter(
+            Filter::new(Action::Drop)
+                .from(2)
+                .msg_type(MessageType::MsgHeartbeatResponse),
+        );
+        nw.add_filter(Filter::new(Action::Duplicate).to(3));
+        nw.send(vec![heartbeat(1, 2), heartbeat(1, 3)]);
+        assert_eq!(received(&nw, 2), 1);
+        assert_eq!(received(&nw, 3), 2);
+        // 节点 2 的回复被丢弃，节点 3 回复了两次
+        assert_eq!(received(&nw, 1), 2);
+
+        nw.recover();
+        nw.add_filter(Filter::new(Action::Delay(3)).to(2));
+        nw.send(vec![heartbeat(1, 2), heartbeat(1, 3)]);
+        assert_eq!(received(&nw, 2), 2);
+        assert_eq!(received(&nw, 1), 4);
+        let last = nw.peers[&1].received.last().unwrap();
+        assert_eq!(last.from, 2);
+    }
+
+    #[test]
+    fn test_network_partition() {
+        let mut nw = new_network(3, 0);
+        nw.partition(&[&[1], &[2, 3]]);
+        nw.send(vec![heartbeat(1, 2), heartbeat(2, 3)]);
+        assert_eq!(received(&nw, 1), 0);
+        assert_eq!(received(&nw, 2), 1);
+        assert_eq!(received(&nw, 3), 1);
+
+        nw.recover();
+        nw.isolate(3);
+        nw.send(vec![heartbeat(1, 2), heartbeat(1, 3)]);
+        assert_eq!(received(&nw, 1), 1);
+        assert_eq!(received(&nw, 3), 1);
+    }
+
+    #[test]
+    fn test_network_deterministic() {
+        let run = |seed| {
+            let mut nw = new_network(5, seed);
+            nw.set_reorder(true);
+            nw.add_filter(Filter::new(Action::Drop).probability(0.3));
+            let msgs = (2..=5).map(|to| heartbeat(1, to)).collect();
+            nw.send(msgs);
+            nw.peers[&1]
+                .received
+                .iter()
+                .map(|m| m.from)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+    }
+}
@@ -1,4 +1,16 @@
+use std::sync::Arc;
+
+use slog::Logger;
+
+use crate::protos::eraftpb::{HardState, Message, MessageType};
+
+use super::config::Config;
+use super::errors::Result;
+use super::metrics::{Metrics, NoopMetrics};
+use super::raft_log::RaftLog;
+use super::status::Status;
 use super::storage::Storage;
+use super::util;
 // use super::read_only::*;
 
 /// The role of the node.
@@ -59,6 +71,284 @@ pub const INVALID_ID: u64 = 0;
 pub const INVALID_INDEX: u64 = 0;
 
 #[derive(Getters)]
-pub struct Raft<T:Storage>{
+pub struct Raft<T: Storage> {
+    /// 当前的任期
+    pub term: u64,
+    /// 当前投票给对等节点的
+    pub vote: u64,
+    /// 当前节点的ID
+    pub id: u64,
+    /// 当前节点的角色
+    pub state: StateRole,
+    /// 当前已知的领导者
+    pub leader_id: u64,
+    /// 当前持久化的日志
+    pub raft_log: RaftLog<T>,
+    /// 当前保存的信息
+    pub max_inflight: usize,
+    /// 所有信息条目最大的长度
+    pub max_msg_size: u64,
+    /// 对等节点获取快照，获取follower包含的快照
+    pub pending_request_snapshot: u64,
+    /// 选举优先级，还没有投票逻辑读取该值，目前不影响选举
+    #[get = "pub"]
+    priority: u64,
+    /// 等待发送的消息，应用层应通过 `take_msgs` 取出以便统计批量大小
+    pub msgs: Vec<Message>,
+    /// 是否合并发往同一节点的追加消息
+    batch_append: bool,
+    /// 提交后是否跳过广播提交下标，暂时不起作用
+    skip_bcast_commit: bool,
+    /// 日志记录器
+    pub logger: Logger,
+    /// 运行指标
+    metrics: Arc<dyn Metrics>,
+    /// 上一次检查不变式时的持久化状态
+    #[cfg(feature = "invariants")]
+    prev_hard_state: HardState,
+}
+
+impl<T: Storage> Raft<T> {
+    /// 根据配置跟存储创建一个新的 Raft 节点
+    pub fn new(c: &Config, store: T, logger: &Logger) -> Result<Raft<T>> {
+        c.validate()?;
+        let logger = logger.new(o!("raft_id" => c.id));
+        let raft_state = store.initial_state()?;
+        let mut raft_log = RaftLog::new(store, logger.clone());
+        // 重启时从存储中恢复提交下标，再根据配置恢复应用下标
+        if raft_state.hard_state != HardState::default() {
+            raft_log.commit_to(raft_state.hard_state.commit);
+        }
+        if c.applied > 0 {
+            raft_log.applied_to(c.applied);
+        }
+        info!(
+            logger,
+            "new raft";
+            "term" => raft_state.hard_state.term,
+            "vote" => raft_state.hard_state.vote,
+            "commit" => raft_log.committed,
+            "last_index" => raft_log.last_index(),
+            "priority" => c.priority,
+        );
+        // 以恢复之后的状态作为不变式检查的起点
+        #[cfg(feature = "invariants")]
+        let prev_hard_state = HardState {
+            term: raft_state.hard_state.term,
+            vote: raft_state.hard_state.vote,
+            commit: raft_log.committed,
+            ..Default::default()
+        };
+        Ok(Raft {
+            term: raft_state.hard_state.term,
+            vote: raft_state.hard_state.vote,
+            id: c.id,
+            state: StateRole::Follower,
+            leader_id: INVALID_ID,
+            raft_log,
+            max_inflight: c.max_inflight_msgs,
+            max_msg_size: c.max_size_per_msg,
+            pending_request_snapshot: INVALID_INDEX,
+            priority: c.priority,
+            msgs: Vec::new(),
+            batch_append: c.batch_append,
+            skip_bcast_commit: c.skip_bcast_commit,
+            logger,
+            metrics: Arc::new(NoopMetrics),
+            #[cfg(feature = "invariants")]
+            prev_hard_state,
+        })
+    }
+
+    /// 调整当前节点的选举优先级，目前只会记录下来，不影响选举
+    pub fn set_priority(&mut self, priority: u64) {
+        info!(self.logger, "priority changed"; "prev_priority" => self.priority, "priority" => priority);
+        self.priority = priority;
+    }
+
+    /// 设置收集运行指标的实现
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// 运行时开启或关闭提交后的广播，在实现提交广播之前没有效果
+    pub fn skip_bcast_commit(&mut self, skip: bool) {
+        self.skip_bcast_commit = skip;
+    }
+
+    /// 提交下标推进后，领导者是否需要立即广播给跟随者，目前还没有调用方
+    #[inline]
+    pub fn should_bcast_commit(&self) -> bool {
+        !self.skip_bcast_commit
+    }
+
+    /// 当前需要持久化的状态
+    pub fn hard_state(&self) -> HardState {
+        HardState {
+            term: self.term,
+            vote: self.vote,
+            commit: self.raft_log.committed,
+            ..Default::default()
+        }
+    }
+
+    /// 当前不需要持久化的状态
+    pub fn soft_state(&self) -> SoftState {
+        SoftState {
+            leader_id: self.leader_id,
+            raft_state: self.state,
+        }
+    }
 
-}
\ No newline at end of file
+    /// 当前节点状态的快照，用于管理后台以及排查落后的副本
+    pub fn status(&self) -> Status {
+        Status {
+            id: self.id,
+            hard_state: self.hard_state(),
+            soft_state: self.soft_state(),
+            applied: self.raft_log.applied,
+        }
+    }
+
+    /// 开启 `invariants` 特性时检查节点的不变式: 任期不回退，同一任期内不改投，
+    /// 提交下标不回退，以及日志自身的不变式。每次发送消息时都会自动检查
+    pub fn check_invariants(&mut self) {
+        #[cfg(feature = "invariants")]
+        {
+            let hs = self.hard_state();
+            let prev = &self.prev_hard_state;
+            invariant!(
+                self.logger,
+                hs.term >= prev.term,
+                self.state_string(),
+                "term regressed from {} to {}",
+                prev.term,
+                hs.term
+            );
+            invariant!(
+                self.logger,
+                hs.term != prev.term || prev.vote == INVALID_ID || hs.vote == prev.vote,
+                self.state_string(),
+                "vote changed from {} to {} in term {}",
+                prev.vote,
+                hs.vote,
+                hs.term
+            );
+            invariant!(
+                self.logger,
+                hs.commit >= prev.commit,
+                self.state_string(),
+                "commit regressed from {} to {}",
+                prev.commit,
+                hs.commit
+            );
+            self.prev_hard_state = hs;
+        }
+        self.raft_log.check_invariants();
+    }
+
+    #[cfg(feature = "invariants")]
+    fn state_string(&self) -> String {
+        format!(
+            "id={}, term={}, vote={}, {}",
+            self.id,
+            self.term,
+            self.vote,
+            self.raft_log.to_string()
+        )
+    }
+
+    /// 将消息放入发送队列，开启 batch_append 时尽量与队列中的追加消息合并
+    pub fn send(&mut self, m: Message) {
+        fail_point!("raft_send", |_| {});
+        self.check_invariants();
+        let m = if self.batch_append {
+            match util::try_batch_append(&mut self.msgs, m, self.max_msg_size) {
+                Some(m) => m,
+                None => return,
+            }
+        } else {
+            m
+        };
+        // 被合并的消息不会单独发送，只统计真正进入发送队列的消息
+        self.metrics.message_sent(m.get_msg_type());
+        if m.get_msg_type() == MessageType::MsgSnapshot {
+            self.metrics.snapshot_sent();
+        }
+        self.msgs.push(m);
+    }
+
+    /// 取出发送队列中的所有消息交给传输层。
+    /// 追加消息此时才不会再被合并，因此在这里统计每条追加消息携带的条目数量
+    pub fn take_msgs(&mut self) -> Vec<Message> {
+        let msgs: Vec<Message> = self.msgs.drain(..).collect();
+        for m in &msgs {
+            if m.get_msg_type() == MessageType::MsgAppend {
+                self.metrics.observe_append_batch(m.get_entries().len());
+            }
+        }
+        msgs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::eraftpb::{ConfState, Entry};
+    use crate::storage::MemStorage;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_restart() {
+        let store = MemStorage::new_with_conf_state(ConfState {
+            nodes: vec![1],
+            ..Default::default()
+        });
+        {
+            let mut core = store.wl();
+            core.append(&[new_entry(2, 1), new_entry(3, 2)]).unwrap();
+            core.commit_to(3).unwrap();
+            core.mut_hard_state().vote = 1;
+        }
+        let mut c = Config::new(1);
+        c.applied = 2;
+        let r = Raft::new(&c, store, &crate::default_logger()).unwrap();
+        assert_eq!(r.raft_log.committed, 3);
+        assert_eq!(r.raft_log.applied, 2);
+        assert_eq!(
+            r.hard_state(),
+            HardState {
+                term: 2,
+                vote: 1,
+                commit: 3,
+                ..Default::default()
+            }
+        );
+        assert_eq!(r.status().applied, 2);
+    }
+
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn test_send_failpoint() {
+        let scenario = fail::FailScenario::setup();
+        let store = MemStorage::new_with_conf_state(ConfState {
+            nodes: vec![1],
+            ..Default::default()
+        });
+        let mut r = Raft::new(&Config::new(1), store, &crate::default_logger()).unwrap();
+        fail::cfg("raft_send", "return").unwrap();
+        r.send(Message::default());
+        assert!(r.msgs.is_empty());
+        fail::remove("raft_send");
+        r.send(Message::default());
+        assert_eq!(r.msgs.len(), 1);
+        scenario.teardown();
+    }
+}
@@ -1,5 +1,10 @@
+use std::cmp;
+
+use crate::errors::{Error, Result, StorageError};
 use crate::log_unstable::Unstable;
+use crate::protos::eraftpb::{Entry, Snapshot};
 use crate::storage::Storage;
+use crate::util::limit_size;
 
 use slog::Logger;
 
@@ -13,6 +18,8 @@ pub struct RaftLog<T: Storage> {
     pub committed: u64,
     /// 公式: applied <= committed
     pub applied: u64,
+    /// 已经持久化的最大日志下标，异步落盘时 persisted 可能落后于 last_index
+    pub persisted: u64,
 }
 
 impl<T> ToString for RaftLog<T>
@@ -21,9 +28,10 @@ where
 {
     fn to_string(&self) -> String {
         format!(
-            "commited={}, applied={}, unstable.offset={}, unstable.entries.len()={}",
+            "commited={}, applied={}, persisted={}, unstable.offset={}, unstable.entries.len()={}",
             self.committed,
             self.applied,
+            self.persisted,
             self.unstable.offset,
             self.unstable.entries.len(),
         )
@@ -38,7 +46,506 @@ impl<T: Storage> RaftLog<T> {
             store,
             committed: first_index - 1,
             applied: first_index - 1,
+            persisted: last_index,
             unstable: Unstable::new(last_index + 1, logger),
         }
     }
+
+    /// 返回第一个日志条目的下标
+    pub fn first_index(&self) -> u64 {
+        match self.unstable.maybe_first_index() {
+            Some(idx) => idx,
+            None => self.store.first_index().unwrap(),
+        }
+    }
+
+    /// 返回最后一个日志条目的下标
+    pub fn last_index(&self) -> u64 {
+        match self.unstable.maybe_last_index() {
+            Some(idx) => idx,
+            None => self.store.last_index().unwrap(),
+        }
+    }
+
+    /// 返回日志下标对应的任期，超出日志范围时返回 0
+    pub fn term(&self, idx: u64) -> Result<u64> {
+        let dummy_idx = self.first_index() - 1;
+        if idx < dummy_idx || idx > self.last_index() {
+            return Ok(0);
+        }
+
+        match self.unstable.maybe_term(idx) {
+            Some(term) => Ok(term),
+            None => self.store.term(idx),
+        }
+    }
+
+    /// 追加日志条目到 unstable 中，返回最后一个日志条目的下标
+    ///
+    /// # Panics
+    ///
+    /// 如果追加的日志会覆盖已经提交的日志
+    pub fn append(&mut self, ents: &[Entry]) -> u64 {
+        if ents.is_empty() {
+            return self.last_index();
+        }
+
+        let after = ents[0].index - 1;
+        if after < self.committed {
+            fatal!(
+                self.unstable.logger,
+                "after {} is out of range [committed {}]",
+                after,
+                self.committed
+            )
+        }
+        if after < self.last_index() {
+            info!(
+                self.unstable.logger,
+                "truncate conflicting entries";
+                "first_index" => after + 1,
+                "last_index" => self.last_index(),
+                "term" => ents[0].term,
+            );
+        }
+        // 覆盖了已经持久化的日志，需要等待新的日志重新写盘
+        if after < self.persisted {
+            self.persisted = after;
+        }
+        self.unstable.truncate_and_append(ents);
+        self.check_invariants();
+        self.last_index()
+    }
+
+    /// 返回还没有持久化的日志条目，应用层可以异步地把它们写入 Storage
+    pub fn unstable_entries(&self) -> &[Entry] {
+        &self.unstable.entries
+    }
+
+    /// 返回还没有持久化的快照
+    pub fn unstable_snapshot(&self) -> Option<&Snapshot> {
+        self.unstable.snapshot.as_ref()
+    }
+
+    /// 应用层通知 (index, term) 之前的日志已经写入 Storage。
+    /// 如果这段日志在写盘期间被更高任期的日志覆盖，通知会被忽略并返回 false
+    pub fn on_persist_entries(&mut self, index: u64, term: u64) -> bool {
+        if index <= self.persisted || self.term(index).ok() != Some(term) {
+            debug!(
+                self.unstable.logger,
+                "ignore stale persist notification";
+                "index" => index,
+                "term" => term,
+                "persisted" => self.persisted,
+            );
+            return false;
+        }
+        self.unstable.stable_to(index, term);
+        self.persisted = index;
+        self.check_invariants();
+        true
+    }
+
+    /// 应用层通知下标为 index 的快照已经写入 Storage
+    pub fn on_persist_snap(&mut self, index: u64) {
+        info!(self.unstable.logger, "snapshot persisted"; "index" => index);
+        self.unstable.stable_snap_to(index);
+        if index > self.persisted {
+            self.persisted = index;
+        }
+        self.check_invariants();
+    }
+
+    /// 使用快照替换本地日志，快照之后的日志条目都会被丢弃，提交下标推进到快照的下标
+    ///
+    /// # Panics
+    ///
+    /// 如果快照的下标小于当前的提交下标
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        let index = snapshot.get_metadata().index;
+        if index < self.committed {
+            fatal!(
+                self.unstable.logger,
+                "snapshot index {} is less than committed {}",
+                index,
+                self.committed
+            )
+        }
+        if self.persisted > index {
+            self.persisted = index;
+        }
+        self.committed = index;
+        self.unstable.restore(snapshot);
+        self.check_invariants();
+    }
+
+    /// 推进提交下标，提交下标不会回退
+    ///
+    /// # Panics
+    ///
+    /// 如果 to_commit 超出了最后一个日志条目的下标
+    pub fn commit_to(&mut self, to_commit: u64) {
+        if self.committed >= to_commit {
+            return;
+        }
+        if self.last_index() < to_commit {
+            fatal!(
+                self.unstable.logger,
+                "to_commit {} is out of range [last_index {}]",
+                to_commit,
+                self.last_index()
+            )
+        }
+        fail_point!("raft_log_commit_to");
+        debug!(
+            self.unstable.logger,
+            "commit index advanced";
+            "prev_commit" => self.committed,
+            "commit" => to_commit,
+        );
+        self.committed = to_commit;
+        self.check_invariants();
+    }
+
+    /// 推进应用下标，公式: applied <= committed
+    ///
+    /// # Panics
+    ///
+    /// 如果 idx 大于提交下标或者小于当前的应用下标
+    pub fn applied_to(&mut self, idx: u64) {
+        if idx == 0 {
+            return;
+        }
+        if self.committed < idx || idx < self.applied {
+            fatal!(
+                self.unstable.logger,
+                "applied({}) is out of range [prev_applied({}), committed({})]",
+                idx,
+                self.applied,
+                self.committed
+            )
+        }
+        self.applied = idx;
+        self.check_invariants();
+    }
+
+    /// 开启 `invariants` 特性时检查日志的不变式:
+    /// applied <= committed <= last_index，没有待持久化的快照时 unstable 紧跟在 Storage 之后
+    pub(crate) fn check_invariants(&self) {
+        invariant!(
+            self.unstable.logger,
+            self.applied <= self.committed,
+            self.to_string(),
+            "applied {} > committed {}",
+            self.applied,
+            self.committed
+        );
+        invariant!(
+            self.unstable.logger,
+            self.committed <= self.last_index(),
+            self.to_string(),
+            "committed {} > last_index {}",
+            self.committed,
+            self.last_index()
+        );
+        // 异步落盘时 Storage 中可能还留有被覆盖的旧日志，因此 offset 只需要不超过 Storage 的末尾
+        invariant!(
+            self.unstable.logger,
+            self.unstable.snapshot.is_some()
+                || self.unstable.offset <= self.store.last_index().unwrap() + 1,
+            self.to_string(),
+            "unstable.offset {} > storage last_index {} + 1",
+            self.unstable.offset,
+            self.store.last_index().unwrap()
+        );
+    }
+
+    fn must_check_outofbounds(&self, low: u64, high: u64) -> Option<Error> {
+        if low > high {
+            fatal!(self.unstable.logger, "invalid slice {} > {}", low, high)
+        }
+        let first_index = self.first_index();
+        if low < first_index {
+            return Some(Error::Store(StorageError::Compacted));
+        }
+
+        let last_index = self.last_index();
+        if high > last_index + 1 {
+            fatal!(
+                self.unstable.logger,
+                "slice[{}, {}] out of bound[{}, {}]",
+                low,
+                high,
+                first_index,
+                last_index
+            )
+        }
+        None
+    }
+
+    /// 返回 [low, high) 之间的日志条目，总长度不超过 max_size
+    pub fn slice(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+    ) -> Result<Vec<Entry>> {
+        let max_size = max_size.into();
+        if let Some(err) = self.must_check_outofbounds(low, high) {
+            return Err(err);
+        }
+
+        let mut ents = vec![];
+        if low == high {
+            return Ok(ents);
+        }
+
+        if low < self.unstable.offset {
+            let unstable_high = cmp::min(high, self.unstable.offset);
+            match self.store.entries(low, unstable_high, max_size) {
+                Err(Error::Store(StorageError::Compacted)) => {
+                    return Err(Error::Store(StorageError::Compacted))
+                }
+                Err(e) => fatal!(
+                    self.unstable.logger,
+                    "entries[{}:{}] is unavailable from storage: {:?}",
+                    low,
+                    unstable_high,
+                    e
+                ),
+                Ok(entries) => {
+                    ents = entries;
+                    if (ents.len() as u64) < unstable_high - low {
+                        return Ok(ents);
+                    }
+                }
+            }
+        }
+
+        if high > self.unstable.offset {
+            let offset = self.unstable.offset;
+            let unstable = self.unstable.slice(cmp::max(low, offset), high);
+            ents.extend_from_slice(unstable);
+        }
+        limit_size(&mut ents, max_size);
+        Ok(ents)
+    }
+
+    /// 返回 since_idx 之后所有已经提交但是还没有应用的日志条目
+    pub fn next_entries_since(&self, since_idx: u64) -> Option<Vec<Entry>> {
+        let offset = cmp::max(since_idx + 1, self.first_index());
+        let committed = self.committed;
+        if committed + 1 > offset {
+            match self.slice(offset, committed + 1, None) {
+                Ok(vec) => return Some(vec),
+                Err(e) => fatal!(self.unstable.logger, "{}", e),
+            }
+        }
+        None
+    }
+
+    /// 返回所有已经提交但是还没有应用的日志条目
+    pub fn next_entries(&self) -> Option<Vec<Entry>> {
+        self.next_entries_since(self.applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+    use crate::util::run_model_test;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        Entry {
+            index,
+            term,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_on_persist_entries() {
+        let store = MemStorage::new();
+        let mut raft_log = RaftLog::new(store.clone(), crate::default_logger());
+        assert_eq!(raft_log.persisted, 0);
+        raft_log.append(&[new_entry(1, 1), new_entry(2, 1)]);
+        assert_eq!(raft_log.unstable_entries().len(), 2);
+
+        // 写盘期间下标 2 被更高任期的日志覆盖，旧的通知需要被忽略
+        let persisting = raft_log.unstable_entries().to_vec();
+        raft_log.append(&[new_entry(2, 2), new_entry(3, 2)]);
+        store.wl().append(&persisting).unwrap();
+        assert!(!raft_log.on_persist_entries(2, 1));
+        assert_eq!(raft_log.persisted, 0);
+        assert_eq!(raft_log.unstable.offset, 1);
+
+        let persisting = raft_log.unstable_entries().to_vec();
+        store.wl().append(&persisting).unwrap();
+        assert!(raft_log.on_persist_entries(3, 2));
+        assert_eq!(raft_log.persisted, 3);
+        assert!(raft_log.unstable_entries().is_empty());
+        assert_eq!(raft_log.term(2), Ok(2));
+        assert_eq!(raft_log.last_index(), 3);
+
+        // 重复的通知
+        assert!(!raft_log.on_persist_entries(3, 2));
+    }
+
+    /// RaftLog 的参考模型，terms[i] 为下标 i + 1 的任期
+    #[derive(Debug, Default)]
+    struct Model {
+        terms: Vec<u64>,
+        persisted: u64,
+        committed: u64,
+        applied: u64,
+    }
+
+    impl Model {
+        fn last_index(&self) -> u64 {
+            self.terms.len() as u64
+        }
+
+        fn term(&self, idx: u64) -> u64 {
+            match idx {
+                0 => 0,
+                _ => self.terms.get(idx as usize - 1).cloned().unwrap_or(0),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Append(u64, u64, u64),
+        Persist(u64, u64),
+        Commit(u64),
+        Apply(u64),
+    }
+
+    fn random_op(rng: &mut StdRng, m: &Model) -> Op {
+        let last = m.last_index();
+        match rng.gen_range(0, 10) {
+            0..=3 => Op::Append(
+                rng.gen_range(m.committed, last + 1),
+                rng.gen_range(1, 5),
+                rng.gen_range(1, 5),
+            ),
+            4..=6 if m.persisted < last => {
+                let idx = rng.gen_range(m.persisted + 1, last + 1);
+                // 偶尔模拟已经被覆盖的日志写盘完成的通知
+                let term = if rng.gen_bool(0.8) {
+                    m.term(idx)
+                } else {
+                    rng.gen_range(1, 5)
+                };
+                Op::Persist(idx, term)
+            }
+            7..=8 => Op::Commit(rng.gen_range(m.committed, last + 1)),
+            _ => Op::Apply(rng.gen_range(m.applied, m.committed + 1)),
+        }
+    }
+
+    fn apply_op(raft_log: &mut RaftLog<MemStorage>, m: &mut Model, op: &Op) {
+        match *op {
+            Op::Append(after, n, term) => {
+                let ents: Vec<Entry> = (after + 1..=after + n)
+                    .map(|i| new_entry(i, term))
+                    .collect();
+                assert_eq!(raft_log.append(&ents), after + n);
+                m.terms.truncate(after as usize);
+                m.terms.extend(ents.iter().map(|e| e.term));
+                m.persisted = m.persisted.min(after);
+            }
+            Op::Persist(idx, term) => {
+                let ents: Vec<Entry> = raft_log
+                    .unstable_entries()
+                    .iter()
+                    .take_while(|e| e.index <= idx)
+                    .cloned()
+                    .collect();
+                if term == m.term(idx) {
+                    raft_log.store.wl().append(&ents).unwrap();
+                }
+                let accepted = raft_log.on_persist_entries(idx, term);
+                assert_eq!(accepted, term == m.term(idx));
+                if accepted {
+                    m.persisted = idx;
+                }
+            }
+            Op::Commit(idx) => {
+                raft_log.commit_to(idx);
+                m.committed = m.committed.max(idx);
+            }
+            Op::Apply(idx) => {
+                raft_log.applied_to(idx);
+                m.applied = m.applied.max(idx);
+            }
+        }
+    }
+
+    fn check_invariants(raft_log: &RaftLog<MemStorage>, m: &Model) {
+        assert!(raft_log.applied <= raft_log.committed);
+        assert!(raft_log.committed <= raft_log.last_index());
+        assert_eq!(
+            (raft_log.committed, raft_log.applied, raft_log.persisted),
+            (m.committed, m.applied, m.persisted)
+        );
+        assert_eq!(raft_log.last_index(), m.last_index());
+        assert_eq!(raft_log.unstable.offset, raft_log.persisted + 1);
+        for idx in 0..=m.last_index() + 1 {
+            assert_eq!(raft_log.term(idx), Ok(m.term(idx)), "term({})", idx);
+        }
+        // 已经持久化的日志必须与模型一致
+        for idx in 1..=raft_log.persisted {
+            assert_eq!(
+                raft_log.store.term(idx),
+                Ok(m.term(idx)),
+                "store.term({})",
+                idx
+            );
+        }
+        let ents = raft_log
+            .slice(raft_log.first_index(), raft_log.last_index() + 1, None)
+            .unwrap();
+        for (i, e) in ents.iter().enumerate() {
+            assert_eq!(e.index, i as u64 + 1);
+            assert_eq!(e.term, m.term(e.index));
+        }
+    }
+
+    #[test]
+    fn test_raft_log_model() {
+        run_model_test(
+            || {
+                let raft_log = RaftLog::new(MemStorage::new(), crate::default_logger());
+                (raft_log, Model::default())
+            },
+            |rng, (_, m)| random_op(rng, m),
+            |(raft_log, m), op| {
+                apply_op(raft_log, m, op);
+                check_invariants(raft_log, m);
+            },
+        );
+    }
+
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn test_commit_to_failpoint() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let scenario = fail::FailScenario::setup();
+        let mut raft_log = RaftLog::new(MemStorage::new(), crate::default_logger());
+        raft_log.append(&[new_entry(1, 1), new_entry(2, 1)]);
+        fail::cfg("raft_log_commit_to", "panic").unwrap();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| raft_log.commit_to(2)));
+        assert!(res.is_err());
+        // 在推进提交下标之前崩溃
+        assert_eq!(raft_log.committed, 0);
+        fail::remove("raft_log_commit_to");
+        raft_log.commit_to(2);
+        assert_eq!(raft_log.committed, 2);
+        scenario.teardown();
+    }
 }
@@ -0,0 +1,480 @@
+//! 用户状态机以及把已提交日志应用到状态机的驱动。
+
+use std::cmp;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+use protobuf::Message as PbMessage;
+use slog::Logger;
+
+use crate::errors::{Error, Result, StorageError};
+use crate::protos::eraftpb::{ConfChange, Entry, EntryType, Snapshot};
+use crate::raft_log::RaftLog;
+use crate::storage::Storage;
+
+/// 保存用户数据的状态机
+pub trait StateMachine {
+    /// 按顺序应用一条已经提交的日志条目，包括空条目以及成员变更条目，
+    /// 状态机需要记录该条目的下标作为应用下标
+    fn apply(&mut self, entry: &Entry) -> Result<()>;
+
+    /// 生成当前状态的快照数据
+    fn snapshot(&self) -> Result<Vec<u8>>;
+
+    /// 从快照中恢复状态，恢复之后应用下标为快照的下标
+    fn restore(&mut self, snapshot: &Snapshot) -> Result<()>;
+
+    /// 最后一次应用的日志下标
+    fn applied_index(&self) -> u64;
+}
+
+/// 把 `RaftLog` 中已提交的日志应用到状态机，并且推进 `RaftLog` 的应用下标
+pub struct ApplyDriver<S: StateMachine> {
+    /// 被驱动的状态机
+    pub state_machine: S,
+}
+
+impl<S: StateMachine> ApplyDriver<S> {
+    /// 创建一个驱动
+    pub fn new(state_machine: S) -> ApplyDriver<S> {
+        ApplyDriver { state_machine }
+    }
+
+    /// 应用给定的已提交日志条目，返回其中的成员变更，由调用方交给 Raft 处理。
+    /// 状态机已经应用过的条目会被跳过，因此重启后重放日志是安全的
+    pub fn apply_entries<T: Storage>(
+        &mut self,
+        raft_log: &mut RaftLog<T>,
+        entries: &[Entry],
+    ) -> Result<Vec<ConfChange>> {
+        let (applied, changes) = apply_to(&mut self.state_machine, entries, raft_log.committed)?;
+        raft_log.applied_to(applied);
+        log_conf_changes(&raft_log.unstable.logger, applied, &changes);
+        Ok(changes)
+    }
+
+    /// 应用 `RaftLog` 中所有已提交但是还没有应用的日志条目
+    pub fn apply_committed<T: Storage>(
+        &mut self,
+        raft_log: &mut RaftLog<T>,
+    ) -> Result<Vec<ConfChange>> {
+        match raft_log.next_entries() {
+            Some(entries) => self.apply_entries(raft_log, &entries),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// 使用快照恢复状态机，并且同步 `RaftLog` 的提交以及应用下标。
+    /// 如果本地日志中没有与快照匹配的条目，例如从领导者收到的快照超出了本地日志，
+    /// 本地日志同样会被快照替换。
+    /// 快照不比已经应用的状态新，或者与已经提交的日志冲突时返回 `SnapshotOutOfDate`，
+    /// 此时状态机不会被修改
+    pub fn restore<T: Storage>(
+        &mut self,
+        raft_log: &mut RaftLog<T>,
+        snapshot: &Snapshot,
+    ) -> Result<()> {
+        let (index, term) = (snapshot.get_metadata().index, snapshot.get_metadata().term);
+        let matched = raft_log.term(index).ok() == Some(term);
+        if index <= raft_log.applied || (index < raft_log.committed && !matched) {
+            return Err(Error::Store(StorageError::SnapshotOutOfDate));
+        }
+        self.state_machine.restore(snapshot)?;
+        info!(
+            raft_log.unstable.logger,
+            "restored state machine from snapshot";
+            "index" => index,
+            "term" => term,
+        );
+        if !matched {
+            raft_log.restore(snapshot.clone());
+        }
+        raft_log.commit_to(index);
+        raft_log.applied_to(index);
+        Ok(())
+    }
+}
+
+fn log_conf_changes(logger: &Logger, applied: u64, changes: &[ConfChange]) {
+    for cc in changes {
+        info!(
+            logger,
+            "applied conf change";
+            "change_type" => ?cc.get_change_type(),
+            "node_id" => cc.node_id,
+            "applied" => applied,
+        );
+    }
+}
+
+/// 按顺序应用不超过 `committed` 的条目，返回最后应用的下标以及其中的成员变更。
+/// 条目必须紧跟在状态机的应用下标之后，否则返回 `ViolatesContract`
+fn apply_to<S: StateMachine>(
+    sm: &mut S,
+    entries: &[Entry],
+    committed: u64,
+) -> Result<(u64, Vec<ConfChange>)> {
+    let mut changes = vec![];
+    for e in entries {
+        if e.index <= sm.applied_index() {
+            continue;
+        }
+        // 只能应用已经提交的条目，保证 applied <= committed
+        if e.index > committed {
+            break;
+        }
+        if e.index != sm.applied_index() + 1 {
+            return Err(Error::ViolatesContract(format!(
+                "entry {} is not contiguous with applied index {}",
+                e.index,
+                sm.applied_index()
+            )));
+        }
+        if e.get_entry_type() == EntryType::EntryConfChange {
+            let mut cc = ConfChange::default();
+            cc.merge_from_bytes(&e.data)?;
+            changes.push(cc);
+        }
+        sm.apply(e)?;
+    }
+    Ok((sm.applied_index(), changes))
+}
+
+/// 在独立线程中应用已提交日志的流水线
+///
+/// Raft 线程调用 `dispatch` 把已提交的条目交给应用线程后立即返回，继续处理心跳以及复制，
+/// 再通过 `poll` 获取应用线程的进度并推进 `RaftLog` 的应用下标。
+/// 已经分发但是还没有应用的条目数量达到 `max_lag` 时不再分发，应用层可以据此拒绝新的提议
+pub struct AsyncApplier<S: StateMachine + Send + 'static> {
+    tx: Option<Sender<(u64, Vec<Entry>)>>,
+    rx: Receiver<Result<(u64, Vec<ConfChange>)>>,
+    handle: Option<JoinHandle<S>>,
+    dispatched: u64,
+    max_lag: u64,
+}
+
+impl<S: StateMachine + Send + 'static> AsyncApplier<S> {
+    /// 启动应用线程，`max_lag` 为允许分发但是还没有应用的最大条目数量
+    pub fn spawn(mut state_machine: S, max_lag: u64) -> Result<AsyncApplier<S>> {
+        let dispatched = state_machine.applied_index();
+        let (tx, task_rx) = mpsc::channel::<(u64, Vec<Entry>)>();
+        let (res_tx, rx) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("raft-apply".to_owned())
+            .spawn(move || {
+                for (committed, entries) in task_rx {
+                    let res = apply_to(&mut state_machine, &entries, committed);
+                    let failed = res.is_err();
+                    if res_tx.send(res).is_err() || failed {
+                        break;
+                    }
+                }
+                state_machine
+            })?;
+        Ok(AsyncApplier {
+            tx: Some(tx),
+            rx,
+            handle: Some(handle),
+            dispatched,
+            max_lag: cmp::max(max_lag, 1),
+        })
+    }
+
+    /// 已经分发给应用线程的最大下标
+    pub fn dispatched(&self) -> u64 {
+        self.dispatched
+    }
+
+    /// 分发但是还没有应用的条目是否已经达到上限
+    pub fn is_busy<T: Storage>(&self, raft_log: &RaftLog<T>) -> bool {
+        self.dispatched >= raft_log.applied + self.max_lag
+    }
+
+    /// 把已提交但是还没有分发的条目交给应用线程，返回本次分发的条目数量
+    pub fn dispatch<T: Storage>(&mut self, raft_log: &RaftLog<T>) -> Result<usize> {
+        let low = cmp::max(self.dispatched, raft_log.applied) + 1;
+        let high = cmp::min(raft_log.committed, raft_log.applied + self.max_lag);
+        if low > high {
+            return Ok(0);
+        }
+        let entries = raft_log.slice(low, high + 1, None)?;
+        let n = entries.len();
+        if let Some(last) = entries.last() {
+            self.dispatched = last.index;
+        }
+        if let Some(tx) = self.tx.as_ref() {
+            if tx.send((raft_log.committed, entries)).is_err() {
+                return Err(self.stopped());
+            }
+        }
+        Ok(n)
+    }
+
+    /// 获取应用线程的进度，推进 `RaftLog` 的应用下标，返回已经应用的成员变更
+    pub fn poll<T: Storage>(&mut self, raft_log: &mut RaftLog<T>) -> Result<Vec<ConfChange>> {
+        let mut changes = vec![];
+        loop {
+            match self.rx.try_recv() {
+                Ok(res) => {
+                    let (applied, mut cc) = res?;
+                    raft_log.applied_to(applied);
+                    log_conf_changes(&raft_log.unstable.logger, applied, &cc);
+                    changes.append(&mut cc);
+                }
+                Err(TryRecvError::Empty) => return Ok(changes),
+                Err(TryRecvError::Disconnected) => {
+                    if self.dispatched > raft_log.applied {
+                        return Err(self.stopped());
+                    }
+                    return Ok(changes);
+                }
+            }
+        }
+    }
+
+    fn stopped(&self) -> Error {
+        Error::ViolatesContract("apply worker has stopped".to_owned())
+    }
+
+    /// 等待所有已经分发的条目应用完成后停止应用线程，返回状态机
+    pub fn stop(mut self) -> S {
+        self.tx.take();
+        self.handle.take().unwrap().join().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+    use std::collections::BTreeMap;
+
+    /// 把 `key=value` 形式的条目写入有序表的状态机
+    #[derive(Default)]
+    struct KvStateMachine {
+        kv: BTreeMap<String, String>,
+        applied: u64,
+    }
+
+    impl StateMachine for KvStateMachine {
+        fn apply(&mut self, entry: &Entry) -> Result<()> {
+            if entry.get_entry_type() == EntryType::EntryNormal && !entry.data.is_empty() {
+                let s = String::from_utf8(entry.data.clone()).unwrap();
+                let mut kv = s.splitn(2, '=');
+                let (k, v) = (kv.next().unwrap(), kv.next().unwrap());
+                self.kv.insert(k.to_owned(), v.to_owned());
+            }
+            self.applied = entry.index;
+            Ok(())
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>> {
+            let pairs: Vec<String> = self
+                .kv
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            Ok(pairs.join(";").into_bytes())
+        }
+
+        fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+            self.kv.clear();
+            let s = String::from_utf8(snapshot.data.clone()).unwrap();
+            for pair in s.split(';').filter(|p| !p.is_empty()) {
+                let mut kv = pair.splitn(2, '=');
+                self.kv
+                    .insert(kv.next().unwrap().to_owned(), kv.next().unwrap().to_owned());
+            }
+            self.applied = snapshot.get_metadata().index;
+            Ok(())
+        }
+
+        fn applied_index(&self) -> u64 {
+            self.applied
+        }
+    }
+
+    fn new_entry(index: u64, data: &str) -> Entry {
+        Entry {
+            index,
+            term: 1,
+            data: data.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_committed() {
+        let store = MemStorage::new();
+        let cc = ConfChange {
+            node_id: 2,
+            ..Default::default()
+        };
+        let conf_change = Entry {
+            entry_type: EntryType::EntryConfChange,
+            index: 3,
+            term: 1,
+            data: cc.write_to_bytes().unwrap(),
+            ..Default::default()
+        };
+        let ents = vec![
+            new_entry(1, "a=1"),
+            new_entry(2, "b=2"),
+            conf_change,
+            new_entry(4, "a=3"),
+        ];
+        store.wl().append(&ents).unwrap();
+
+        let mut raft_log = RaftLog::new(store, crate::default_logger());
+        let mut driver = ApplyDriver::new(KvStateMachine::default());
+        raft_log.commit_to(3);
+        let changes = driver.apply_committed(&mut raft_log).unwrap();
+        assert_eq!(changes, vec![cc]);
+        assert_eq!(raft_log.applied, 3);
+        assert_eq!(driver.state_machine.applied_index(), 3);
+        assert_eq!(driver.state_machine.kv["a"], "1");
+
+        // 没有提交的条目不会被应用，重放已经应用的条目不会修改状态
+        let changes = driver.apply_entries(&mut raft_log, &ents).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(raft_log.applied, 3);
+
+        raft_log.commit_to(4);
+        driver.apply_committed(&mut raft_log).unwrap();
+        assert_eq!(raft_log.applied, 4);
+        assert_eq!(driver.state_machine.kv["a"], "3");
+        assert_eq!(
+            driver.state_machine.snapshot().unwrap(),
+            b"a=3;b=2".to_vec()
+        );
+        assert!(raft_log.next_entries().is_none());
+    }
+
+    #[test]
+    fn test_restore() {
+        let store = MemStorage::new();
+        store
+            .wl()
+            .append(&[new_entry(1, "a=1"), new_entry(2, "b=2")])
+            .unwrap();
+        let mut raft_log = RaftLog::new(store, crate::default_logger());
+        let mut driver = ApplyDriver::new(KvStateMachine::default());
+
+        let mut snap = Snapshot {
+            data: b"c=3".to_vec(),
+            ..Default::default()
+        };
+        snap.mut_metadata().index = 2;
+        driver.restore(&mut raft_log, &snap).unwrap();
+        assert_eq!(raft_log.committed, 2);
+        assert_eq!(raft_log.applied, 2);
+        assert_eq!(driver.state_machine.kv.len(), 1);
+        assert!(driver.apply_committed(&mut raft_log).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_beyond_last_index() {
+        let store = MemStorage::new();
+        store
+            .wl()
+            .append(&[new_entry(1, "a=1"), new_entry(2, "b=2")])
+            .unwrap();
+        let mut raft_log = RaftLog::new(store, crate::default_logger());
+        raft_log.commit_to(1);
+        let mut driver = ApplyDriver::new(KvStateMachine::default());
+
+        let mut snap = Snapshot {
+            data: b"c=3;d=4".to_vec(),
+            ..Default::default()
+        };
+        snap.mut_metadata().index = 5;
+        snap.mut_metadata().term = 2;
+        driver.restore(&mut raft_log, &snap).unwrap();
+        assert_eq!(raft_log.last_index(), 5);
+        assert_eq!(raft_log.term(5), Ok(2));
+        assert_eq!(raft_log.committed, 5);
+        assert_eq!(raft_log.applied, 5);
+        assert_eq!(raft_log.unstable_snapshot(), Some(&snap));
+        assert_eq!(driver.state_machine.kv.len(), 2);
+        assert!(driver.apply_committed(&mut raft_log).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restore_out_of_date() {
+        let store = MemStorage::new();
+        store
+            .wl()
+            .append(&[
+                new_entry(1, "a=1"),
+                new_entry(2, "b=2"),
+                new_entry(3, "c=3"),
+            ])
+            .unwrap();
+        let mut raft_log = RaftLog::new(store, crate::default_logger());
+        raft_log.commit_to(3);
+        let mut driver = ApplyDriver::new(KvStateMachine::default());
+        driver.apply_committed(&mut raft_log).unwrap();
+        assert_eq!(raft_log.applied, 3);
+
+        // 不比已经应用的状态新的快照
+        let mut snap = Snapshot {
+            data: b"z=0".to_vec(),
+            ..Default::default()
+        };
+        snap.mut_metadata().index = 2;
+        snap.mut_metadata().term = 1;
+        assert_eq!(
+            driver.restore(&mut raft_log, &snap),
+            Err(Error::Store(StorageError::SnapshotOutOfDate))
+        );
+        assert_eq!(driver.state_machine.kv.len(), 3);
+        assert_eq!(driver.state_machine.applied, 3);
+        assert_eq!((raft_log.committed, raft_log.applied), (3, 3));
+    }
+
+    #[test]
+    fn test_apply_gap() {
+        let store = MemStorage::new();
+        let ents: Vec<Entry> = (1..=6).map(|i| new_entry(i, "a=1")).collect();
+        store.wl().append(&ents).unwrap();
+        let mut raft_log = RaftLog::new(store, crate::default_logger());
+        raft_log.commit_to(6);
+        // 应用下标恢复为 5，但是状态机是空的
+        raft_log.applied_to(5);
+        let mut driver = ApplyDriver::new(KvStateMachine::default());
+        match driver.apply_committed(&mut raft_log) {
+            Err(Error::ViolatesContract(_)) => {}
+            res => panic!("expect ViolatesContract, got {:?}", res),
+        }
+        assert_eq!(driver.state_machine.applied, 0);
+        assert_eq!(raft_log.applied, 5);
+    }
+
+    #[test]
+    fn test_async_apply() {
+        let store = MemStorage::new();
+        let ents: Vec<Entry> = (1..=10)
+            .map(|i| new_entry(i, &format!("k{}={}", i % 3, i)))
+            .collect();
+        store.wl().append(&ents).unwrap();
+        let mut raft_log = RaftLog::new(store, crate::default_logger());
+        raft_log.commit_to(10);
+
+        let mut applier = AsyncApplier::spawn(KvStateMachine::default(), 4).unwrap();
+        // 应用线程落后时最多分发 max_lag 个条目
+        assert_eq!(applier.dispatch(&raft_log).unwrap(), 4);
+        assert!(applier.is_busy(&raft_log));
+        assert_eq!(applier.dispatch(&raft_log).unwrap(), 0);
+
+        while raft_log.applied < 10 {
+            applier.poll(&mut raft_log).unwrap();
+            applier.dispatch(&raft_log).unwrap();
+            assert!(raft_log.applied <= raft_log.committed);
+            assert!(applier.dispatched() <= raft_log.applied + 4);
+            thread::yield_now();
+        }
+        let sm = applier.stop();
+        assert_eq!(sm.applied_index(), 10);
+        assert_eq!(sm.kv["k1"], "10");
+    }
+}
@@ -0,0 +1,86 @@
+//! 节点状态的快照，可以编码为 JSON 供管理后台以及命令行工具展示。
+
+use crate::protos::eraftpb::HardState;
+use crate::raft::SoftState;
+
+/// 某一时刻的节点状态
+#[derive(Debug, Default, PartialEq)]
+pub struct Status {
+    /// 节点ID
+    pub id: u64,
+    /// 需要持久化的状态
+    pub hard_state: HardState,
+    /// 不需要持久化的状态
+    pub soft_state: SoftState,
+    /// 已经应用的日志下标
+    pub applied: u64,
+}
+
+impl Status {
+    /// 编码为 JSON，字段顺序固定
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"id\":{},\"hard_state\":{{\"term\":{},\"vote\":{},\"commit\":{}}},\
+             \"soft_state\":{{\"leader_id\":{},\"raft_state\":\"{:?}\"}},\"applied\":{}}}",
+            self.id,
+            self.hard_state.term,
+            self.hard_state.vote,
+            self.hard_state.commit,
+            self.soft_state.leader_id,
+            self.soft_state.raft_state,
+            self.applied,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::protos::eraftpb::{Entry, HardState};
+    use crate::raft::{Raft, SoftState, StateRole};
+    use crate::storage::MemStorage;
+
+    #[test]
+    fn test_status() {
+        let l = crate::default_logger();
+        let mut r = Raft::new(&Config::new(1), MemStorage::new(), &l).unwrap();
+        r.term = 3;
+        r.vote = 2;
+        r.leader_id = 2;
+        let ents: Vec<Entry> = (1..=3)
+            .map(|index| Entry {
+                index,
+                term: 3,
+                ..Default::default()
+            })
+            .collect();
+        r.raft_log.append(&ents);
+        r.raft_log.commit_to(3);
+        r.raft_log.applied_to(2);
+
+        let status = r.status();
+        assert_eq!(status.id, 1);
+        assert_eq!(
+            status.hard_state,
+            HardState {
+                term: 3,
+                vote: 2,
+                commit: 3,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            status.soft_state,
+            SoftState {
+                leader_id: 2,
+                raft_state: StateRole::Follower,
+            }
+        );
+        assert_eq!(status.applied, 2);
+        assert_eq!(
+            status.to_json(),
+            "{\"id\":1,\"hard_state\":{\"term\":3,\"vote\":2,\"commit\":3},\
+             \"soft_state\":{\"leader_id\":2,\"raft_state\":\"Follower\"},\"applied\":2}"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use crate::protos::eraftpb::*;
 use crate::errors;
 use crate::errors::*;
+use crate::util::limit_size;
+use std::cmp;
 use std::sync::*;
 
 #[derive(Debug,Clone,Default)]
@@ -108,6 +110,7 @@ impl MemStorageCore{
         assert!(self.has_entry_at(index),"commit_to {} but the entry not exists",index);
 
         let diff=(index-self.entries[0].index)as usize;
+        fail_point!("memstorage_commit_to",|_|Err(Error::Store(StorageError::Other("memstorage_commit_to".into()))));
         self.raft_state.hard_state.commit=index;
         self.raft_state.hard_state.term=self.entries[diff].term;
         Ok(())
@@ -152,8 +155,61 @@ impl MemStorageCore{
         if self.first_index()>index{
             return Err(Error::Store(StorageError::SnapshotOutOfDate));
         }
+        fail_point!("memstorage_apply_snapshot",|_|Err(Error::Store(StorageError::Other("memstorage_apply_snapshot".into()))));
+
+        self.snapshot_metadata=meta.clone();
+        self.raft_state.hard_state.term=cmp::max(self.raft_state.hard_state.term,term);
+        self.raft_state.hard_state.commit=index;
+        self.entries.clear();
+        self.raft_state.conf_state=meta.take_conf_state();
+        Ok(())
+    }
+
+    /// 根据当前的状态生成快照
+    fn snapshot(&self)->Snapshot{
+        let mut snapshot=Snapshot::default();
+        let meta=snapshot.mut_metadata();
+        meta.index=self.raft_state.hard_state.commit;
+        meta.term=match meta.index.cmp(&self.snapshot_metadata.index){
+            cmp::Ordering::Equal=>self.snapshot_metadata.term,
+            cmp::Ordering::Greater=>{
+                let offset=self.entries[0].index;
+                self.entries[(meta.index-offset) as usize].term
+            }
+            cmp::Ordering::Less=>{
+                panic!("commit {} < snapshot_metadata.index {}",meta.index,self.snapshot_metadata.index);
+            }
+        };
+        meta.set_conf_state(self.raft_state.conf_state.clone());
+        snapshot
+    }
+
+    /// 追加日志条目到存储中，与已有日志冲突的部分将被覆盖
+    /// # Panics
+    ///
+    /// 如果覆盖了已经压缩的日志，或者追加的日志与已有日志不连续
+    pub fn append(&mut self,ents:&[Entry])->errors::Result<()>{
+        if ents.is_empty(){
+            return Ok(());
+        }
+        if self.first_index()>ents[0].index{
+            panic!("overwrite compacted raft logs, compacted: {}, append: {}",self.first_index()-1,ents[0].index);
+        }
+        if self.last_index()+1<ents[0].index{
+            panic!("raft logs should be continuous, last index: {}, new appended: {}",self.last_index(),ents[0].index);
+        }
+        fail_point!("memstorage_append",|_|Err(Error::Store(StorageError::Other("memstorage_append".into()))));
+
+        let diff=ents[0].index-self.first_index();
+        self.entries.drain(diff as usize..);
+        self.entries.extend_from_slice(ents);
         Ok(())
     }
+
+    /// 下一次调用 `snapshot` 时返回 SnapshotTemporarilyUnavailable
+    pub fn trigger_snap_unavailable(&mut self){
+        self.trigger_snap_unavailable=true;
+    }
 }
 
 
@@ -162,8 +218,256 @@ pub struct MemStorage{
     core:Arc<RwLock<MemStorageCore>>,
 }
 
-// impl Storage for MemStorage{
-//     fn initial_state(&self)->errors::Result<RaftState>{
-//         Ok
-//     }
-// }
\ No newline at end of file
+impl MemStorage{
+    /// 创建一个空的 MemStorage
+    pub fn new()->MemStorage{
+        MemStorage::default()
+    }
+
+    /// 使用给定的配置创建一个已经初始化的 MemStorage
+    pub fn new_with_conf_state(conf_state:ConfState)->MemStorage{
+        let store=MemStorage::new();
+        store.initialize_with_conf_state(conf_state);
+        store
+    }
+
+    /// 使用给定的配置初始化存储，日志下标跟任期均从 1 开始
+    /// # Panics
+    ///
+    /// 如果存储已经初始化过
+    pub fn initialize_with_conf_state(&self,conf_state:ConfState){
+        assert!(!self.initial_state().unwrap().initialized());
+        let mut core=self.wl();
+        core.snapshot_metadata.index=1;
+        core.snapshot_metadata.term=1;
+        core.raft_state.hard_state.commit=1;
+        core.raft_state.hard_state.term=1;
+        core.raft_state.conf_state=conf_state;
+    }
+
+    /// 获取读锁
+    pub fn rl(&self)->RwLockReadGuard<'_,MemStorageCore>{
+        self.core.read().unwrap()
+    }
+
+    /// 获取写锁
+    pub fn wl(&self)->RwLockWriteGuard<'_,MemStorageCore>{
+        self.core.write().unwrap()
+    }
+}
+
+impl Storage for MemStorage{
+    fn initial_state(&self)->errors::Result<RaftState>{
+        Ok(self.rl().raft_state.clone())
+    }
+
+    fn entries(&self,low:u64,high:u64,max_size:impl Into<Option<u64>>)->errors::Result<Vec<Entry>>{
+        let max_size=max_size.into();
+        let core=self.rl();
+        if low<core.first_index(){
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        if high>core.last_index()+1{
+            panic!("index out of bound (last: {}, high: {})",core.last_index()+1,high);
+        }
+        if low==high{
+            return Ok(vec![]);
+        }
+
+        let offset=core.entries[0].index;
+        let lo=(low-offset) as usize;
+        let hi=(high-offset) as usize;
+        let mut ents=core.entries[lo..hi].to_vec();
+        limit_size(&mut ents,max_size);
+        Ok(ents)
+    }
+
+    fn term(&self,idx:u64)->errors::Result<u64>{
+        let core=self.rl();
+        if idx==core.snapshot_metadata.index{
+            return Ok(core.snapshot_metadata.term);
+        }
+        if idx<core.first_index(){
+            return Err(Error::Store(StorageError::Compacted));
+        }
+        if idx>core.last_index(){
+            return Err(Error::Store(StorageError::Unavailable));
+        }
+        let offset=core.entries[0].index;
+        Ok(core.entries[(idx-offset) as usize].term)
+    }
+
+    fn first_index(&self)->errors::Result<u64>{
+        Ok(self.rl().first_index())
+    }
+
+    fn last_index(&self)->errors::Result<u64>{
+        Ok(self.rl().last_index())
+    }
+
+    fn snapshot(&self,request_index:u64)->errors::Result<Snapshot>{
+        let mut core=self.wl();
+        if core.trigger_snap_unavailable{
+            core.trigger_snap_unavailable=false;
+            return Err(Error::Store(StorageError::SnapshotTemporarilyUnavailable));
+        }
+        let mut snap=core.snapshot();
+        if snap.get_metadata().index<request_index{
+            snap.mut_metadata().index=request_index;
+        }
+        Ok(snap)
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use protobuf::Message as PbMessage;
+
+    pub(super) fn new_entry(index:u64,term:u64)->Entry{
+        Entry{index,term,..Default::default()}
+    }
+
+    pub(super) fn new_conf_state(nodes:Vec<u64>)->ConfState{
+        ConfState{nodes,..Default::default()}
+    }
+
+    fn size_of(e:&Entry)->u64{
+        u64::from(e.compute_size())
+    }
+
+    fn new_storage(ents:&[Entry])->MemStorage{
+        let storage=MemStorage::new();
+        storage.wl().entries=ents.to_vec();
+        storage.wl().snapshot_metadata.index=ents[0].index;
+        storage.wl().snapshot_metadata.term=ents[0].term;
+        storage
+    }
+
+    #[test]
+    fn test_storage_term(){
+        let ents=vec![new_entry(3,3),new_entry(4,4),new_entry(5,5)];
+        let storage=new_storage(&ents);
+        let tests=vec![
+            (2,Err(Error::Store(StorageError::Compacted))),
+            (3,Ok(3)),
+            (4,Ok(4)),
+            (5,Ok(5)),
+            (6,Err(Error::Store(StorageError::Unavailable))),
+        ];
+        for (i,(idx,wterm)) in tests.into_iter().enumerate(){
+            assert_eq!(storage.term(idx),wterm,"#{}",i);
+        }
+    }
+
+    #[test]
+    fn test_storage_entries(){
+        let ents=vec![new_entry(3,3),new_entry(4,4),new_entry(5,5),new_entry(6,6)];
+        let max_u64=u64::MAX;
+        let storage=new_storage(&ents);
+        let tests=vec![
+            (2,6,max_u64,Err(Error::Store(StorageError::Compacted))),
+            (3,4,max_u64,Ok(vec![new_entry(3,3)])),
+            (4,5,max_u64,Ok(vec![new_entry(4,4)])),
+            (4,6,max_u64,Ok(vec![new_entry(4,4),new_entry(5,5)])),
+            (4,7,max_u64,Ok(vec![new_entry(4,4),new_entry(5,5),new_entry(6,6)])),
+            // 即使超出长度限制，也至少返回一个条目
+            (4,7,0,Ok(vec![new_entry(4,4)])),
+            (4,7,size_of(&ents[1])+size_of(&ents[2]),Ok(vec![new_entry(4,4),new_entry(5,5)])),
+            (4,7,size_of(&ents[1])+size_of(&ents[2])+size_of(&ents[3])/2,Ok(vec![new_entry(4,4),new_entry(5,5)])),
+            (4,7,size_of(&ents[1])+size_of(&ents[2])+size_of(&ents[3]),Ok(vec![new_entry(4,4),new_entry(5,5),new_entry(6,6)])),
+        ];
+        for (i,(lo,hi,max_size,wentries)) in tests.into_iter().enumerate(){
+            assert_eq!(storage.entries(lo,hi,max_size),wentries,"#{}",i);
+        }
+    }
+
+    #[test]
+    fn test_storage_append(){
+        let ents=vec![new_entry(3,3),new_entry(4,4),new_entry(5,5)];
+        let tests=vec![
+            (vec![new_entry(4,6),new_entry(5,6)],vec![new_entry(3,3),new_entry(4,6),new_entry(5,6)]),
+            (vec![new_entry(4,4),new_entry(5,5),new_entry(6,5)],vec![new_entry(3,3),new_entry(4,4),new_entry(5,5),new_entry(6,5)]),
+            (vec![new_entry(6,5)],vec![new_entry(3,3),new_entry(4,4),new_entry(5,5),new_entry(6,5)]),
+        ];
+        for (i,(entries,wentries)) in tests.into_iter().enumerate(){
+            let storage=new_storage(&ents);
+            storage.wl().append(&entries).unwrap();
+            assert_eq!(storage.rl().entries,wentries,"#{}",i);
+        }
+    }
+
+    #[test]
+    fn test_storage_apply_snapshot(){
+        let storage=MemStorage::new_with_conf_state(new_conf_state(vec![1,2,3]));
+        let mut snap=Snapshot::default();
+        snap.mut_metadata().index=4;
+        snap.mut_metadata().term=4;
+        snap.mut_metadata().mut_conf_state().nodes=vec![1,2];
+        storage.wl().apply_snapshot(snap.clone()).unwrap();
+        assert_eq!(storage.first_index(),Ok(5));
+        assert_eq!(storage.term(4),Ok(4));
+        assert_eq!(storage.initial_state().unwrap().conf_state.nodes,vec![1,2]);
+
+        snap.mut_metadata().index=3;
+        assert_eq!(storage.wl().apply_snapshot(snap),Err(Error::Store(StorageError::SnapshotOutOfDate)));
+    }
+}
+
+#[cfg(all(test,feature="failpoints"))]
+mod failpoints_test{
+    use super::*;
+    use super::test::{new_entry,new_conf_state};
+    use crate::raft_log::RaftLog;
+    use fail::FailScenario;
+
+    #[test]
+    fn test_append_failed(){
+        let scenario=FailScenario::setup();
+        let storage=MemStorage::new_with_conf_state(new_conf_state(vec![1]));
+        fail::cfg("memstorage_append","return").unwrap();
+        assert!(storage.wl().append(&[new_entry(2,1)]).is_err());
+        assert_eq!(storage.last_index(),Ok(1));
+        scenario.teardown();
+    }
+
+    #[test]
+    fn test_apply_snapshot_failed(){
+        let scenario=FailScenario::setup();
+        let storage=MemStorage::new_with_conf_state(new_conf_state(vec![1]));
+        let mut snap=Snapshot::default();
+        snap.mut_metadata().index=5;
+        snap.mut_metadata().term=2;
+        fail::cfg("memstorage_apply_snapshot","return").unwrap();
+        assert!(storage.wl().apply_snapshot(snap).is_err());
+        assert_eq!(storage.first_index(),Ok(2));
+        assert_eq!(storage.initial_state().unwrap().hard_state.commit,1);
+        scenario.teardown();
+    }
+
+    /// 日志已经持久化，但是在更新 HardState 之前节点崩溃
+    #[test]
+    fn test_crash_before_hard_state_persisted(){
+        let scenario=FailScenario::setup();
+        let storage=MemStorage::new_with_conf_state(new_conf_state(vec![1]));
+        storage.wl().append(&[new_entry(2,1),new_entry(3,2)]).unwrap();
+
+        fail::cfg("memstorage_commit_to","return").unwrap();
+        assert!(storage.wl().commit_to(3).is_err());
+        fail::remove("memstorage_commit_to");
+
+        // 重启之后日志仍然存在，但是提交下标停留在崩溃之前
+        let hs=storage.initial_state().unwrap().hard_state;
+        assert_eq!(hs.commit,1);
+        assert_eq!(hs.term,1);
+        let mut raft_log=RaftLog::new(storage.clone(),crate::default_logger());
+        assert_eq!(raft_log.last_index(),3);
+        assert_eq!(raft_log.term(3),Ok(2));
+        assert_eq!(raft_log.committed,hs.commit);
+
+        raft_log.commit_to(3);
+        storage.wl().commit_to(3).unwrap();
+        assert_eq!(storage.initial_state().unwrap().hard_state.commit,3);
+        scenario.teardown();
+    }
+}
\ No newline at end of file
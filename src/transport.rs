@@ -0,0 +1,483 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use protobuf::Message as PbMessage;
+use slog::Logger;
+
+use crate::errors::{Error, Result};
+use crate::metrics::{Metrics, NoopMetrics};
+use crate::protos::eraftpb::{Message, MessageType};
+
+/// 每个对等节点默认的发送队列长度
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+/// 单条消息的最大长度，超过该长度的帧被视为损坏
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// 对端停止读取或者网络分区时，写入最多阻塞这么久，避免发送线程无法退出
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn check_frame_size(len: usize) -> Result<()> {
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame size {} exceeds {}", len, MAX_FRAME_SIZE),
+        )));
+    }
+    Ok(())
+}
+
+/// 将消息编码为 `4 字节大端长度 + protobuf 数据` 的帧写入，
+/// 超过 `MAX_FRAME_SIZE` 的消息会返回错误而不是发送给对端
+pub fn write_message<W: Write>(w: &mut W, m: &Message) -> Result<()> {
+    let data = m.write_to_bytes()?;
+    check_frame_size(data.len())?;
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(&data)?;
+    Ok(())
+}
+
+/// 读取一帧消息，对端在两帧之间正常关闭连接时返回 `None`，
+/// 在帧的中间关闭连接时返回 `UnexpectedEof` 错误
+pub fn read_message<R: Read>(r: &mut R) -> Result<Option<Message>> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("frame header truncated after {} bytes", read),
+                )))
+            }
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    check_frame_size(len)?;
+    let mut data = vec![0; len];
+    r.read_exact(&mut data)?;
+    Ok(Some(protobuf::parse_from_bytes(&data)?))
+}
+
+/// 基于 TCP 的消息传输模块
+///
+/// 每个对等节点维护一个带有界队列的发送线程，连接断开后会在下一条消息到来时重连。
+/// 从网络中收到的消息以及发送失败时生成的 `MsgUnreachable` 都会放入同一个接收队列，
+/// 应用层只需要把它们依次交给 Raft 处理。
+/// 调用 `shutdown` 或者销毁传输模块时会关闭所有连接并等待后台线程退出
+pub struct Transport {
+    id: u64,
+    queue_size: usize,
+    peers: HashMap<u64, SyncSender<Message>>,
+    workers: Vec<JoinHandle<()>>,
+    listeners: Vec<Listener>,
+    closed: Arc<AtomicBool>,
+    inbound: Sender<Message>,
+    metrics: Arc<dyn Metrics>,
+    logger: Logger,
+}
+
+/// 已接受的连接，保留一份 `TcpStream` 用于在关闭时中断读取
+type Connections = Arc<Mutex<Vec<(TcpStream, JoinHandle<()>)>>>;
+
+/// 监听线程以及它接受的连接
+struct Listener {
+    addr: SocketAddr,
+    handle: JoinHandle<()>,
+    conns: Connections,
+}
+
+impl Transport {
+    /// 创建当前节点的传输模块，返回的接收端用于获取发给当前节点的消息
+    pub fn new(id: u64, logger: &Logger) -> (Transport, Receiver<Message>) {
+        Transport::with_queue_size(id, DEFAULT_QUEUE_SIZE, logger)
+    }
+
+    /// 创建一个指定发送队列长度的传输模块
+    pub fn with_queue_size(
+        id: u64,
+        queue_size: usize,
+        logger: &Logger,
+    ) -> (Transport, Receiver<Message>) {
+        let (tx, rx) = mpsc::channel();
+        let transport = Transport {
+            id,
+            queue_size,
+            peers: HashMap::new(),
+            workers: vec![],
+            listeners: vec![],
+            closed: Arc::new(AtomicBool::new(false)),
+            inbound: tx,
+            metrics: Arc::new(NoopMetrics),
+            logger: logger.new(o!("raft_id" => id)),
+        };
+        (transport, rx)
+    }
+
+    /// 设置收集运行指标的实现，需要在 `listen` 之前调用
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// 监听指定地址，接收其他节点发来的消息，返回实际监听的地址
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let conns: Connections = Arc::new(Mutex::new(Vec::new()));
+        let accepted = conns.clone();
+        let closed = self.closed.clone();
+        let inbound = self.inbound.clone();
+        let metrics = self.metrics.clone();
+        let logger = self.logger.clone();
+        let handle = thread::Builder::new()
+            .name(format!("raft-transport-listener-{}", self.id))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if closed.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let (stream, clone) = match stream.and_then(|s| {
+                        let clone = s.try_clone()?;
+                        Ok((s, clone))
+                    }) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!(logger, "accept connection failed"; "err" => %e);
+                            continue;
+                        }
+                    };
+                    let inbound = inbound.clone();
+                    let metrics = metrics.clone();
+                    let logger = logger.clone();
+                    let handle =
+                        thread::spawn(move || serve_connection(stream, inbound, metrics, logger));
+                    let mut conns = accepted.lock().unwrap();
+                    conns.retain(|(_, h)| !h.is_finished());
+                    conns.push((clone, handle));
+                }
+            })?;
+        self.listeners.push(Listener {
+            addr: local_addr,
+            handle,
+            conns,
+        });
+        Ok(local_addr)
+    }
+
+    /// 添加对等节点，已存在的节点会使用新的地址重新连接
+    pub fn add_peer(&mut self, id: u64, addr: SocketAddr) -> Result<()> {
+        let (tx, rx) = mpsc::sync_channel(self.queue_size);
+        let mut worker = PeerWorker {
+            id: self.id,
+            peer: id,
+            addr,
+            conn: None,
+            inbound: self.inbound.clone(),
+            logger: self.logger.new(o!("peer" => id)),
+        };
+        let handle = thread::Builder::new()
+            .name(format!("raft-transport-{}-{}", self.id, id))
+            .spawn(move || worker.run(rx))?;
+        self.workers.retain(|h| !h.is_finished());
+        self.workers.push(handle);
+        self.peers.insert(id, tx);
+        Ok(())
+    }
+
+    /// 删除对等节点，队列中尚未发送的消息会被丢弃
+    pub fn remove_peer(&mut self, id: u64) {
+        self.peers.remove(&id);
+    }
+
+    /// 把消息放入目标节点的发送队列。
+    /// 队列已满时返回 `WouldBlock` 错误，调用方应当降低发送速度
+    pub fn send(&self, m: Message) -> Result<()> {
+        let tx = match self.peers.get(&m.to) {
+            Some(tx) => tx,
+            None => return Err(Error::NotExists(m.to, "transport peers")),
+        };
+        match tx.try_send(m) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(m)) => Err(Error::Io(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("send queue to {} is full", m.to),
+            ))),
+            Err(TrySendError::Disconnected(m)) => Err(Error::NotExists(m.to, "transport peers")),
+        }
+    }
+
+    /// 停止监听，关闭所有连接并等待后台线程退出。发送队列中尚未发送的消息会被丢弃，
+    /// 正在写入的发送线程最多在写超时之后退出
+    pub fn shutdown(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for l in self.listeners.drain(..) {
+            // 监听线程阻塞在 accept 上，主动连接一次将其唤醒
+            let mut addr = l.addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            let _ = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT);
+            let _ = l.handle.join();
+            for (stream, handle) in l.conns.lock().unwrap().drain(..) {
+                let _ = stream.shutdown(Shutdown::Both);
+                let _ = handle.join();
+            }
+        }
+        // 发送队列关闭之后发送线程会自行退出
+        self.peers.clear();
+        for handle in self.workers.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn serve_connection(
+    stream: TcpStream,
+    inbound: Sender<Message>,
+    metrics: Arc<dyn Metrics>,
+    logger: Logger,
+) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        match read_message(&mut reader) {
+            Ok(Some(m)) => {
+                metrics.message_received(m.get_msg_type());
+                if inbound.send(m).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                warn!(logger, "read message failed"; "err" => %e);
+                return;
+            }
+        }
+    }
+}
+
+struct PeerWorker {
+    id: u64,
+    peer: u64,
+    addr: SocketAddr,
+    conn: Option<BufWriter<TcpStream>>,
+    inbound: Sender<Message>,
+    logger: Logger,
+}
+
+impl PeerWorker {
+    fn run(&mut self, rx: Receiver<Message>) {
+        while let Ok(m) = rx.recv() {
+            let mut res = self.write(&m);
+            // 尽量把队列中已有的消息一起写出，减少 flush 次数
+            while res.is_ok() {
+                match rx.try_recv() {
+                    Ok(m) => res = self.write(&m),
+                    Err(_) => break,
+                }
+            }
+            if res.is_ok() {
+                res = self.conn.as_mut().unwrap().flush().map_err(Error::from);
+            }
+            if let Err(e) = res {
+                // 丢弃队列中剩余的消息，只报告一次不可达，避免每条消息都重新连接一次
+                let mut dropped = 0;
+                while rx.try_recv().is_ok() {
+                    dropped += 1;
+                }
+                warn!(
+                    self.logger,
+                    "send message failed";
+                    "addr" => %self.addr,
+                    "dropped" => dropped,
+                    "err" => %e,
+                );
+                self.conn = None;
+                self.report_unreachable();
+            }
+        }
+    }
+
+    fn write(&mut self, m: &Message) -> Result<()> {
+        if self.conn.is_none() {
+            let stream = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)?;
+            stream.set_nodelay(true)?;
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            self.conn = Some(BufWriter::new(stream));
+        }
+        write_message(self.conn.as_mut().unwrap(), m)
+    }
+
+    fn report_unreachable(&self) {
+        let mut m = Message::default();
+        m.set_msg_type(MessageType::MsgUnreachable);
+        m.from = self.peer;
+        m.to = self.id;
+        let _ = self.inbound.send(m);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::eraftpb::Entry;
+    use std::io::Cursor;
+
+    fn new_message(from: u64, to: u64, t: MessageType) -> Message {
+        let mut m = Message::default();
+        m.set_msg_type(t);
+        m.from = from;
+        m.to = to;
+        m.term = 3;
+        m
+    }
+
+    #[test]
+    fn test_frame_codec() {
+        let mut buf = vec![];
+        let m1 = new_message(1, 2, MessageType::MsgAppend);
+        let m2 = new_message(2, 1, MessageType::MsgHeartbeat);
+        write_message(&mut buf, &m1).unwrap();
+        write_message(&mut buf, &m2).unwrap();
+
+        let mut r = Cursor::new(buf);
+        assert_eq!(read_message(&mut r).unwrap(), Some(m1));
+        assert_eq!(read_message(&mut r).unwrap(), Some(m2));
+        assert_eq!(read_message(&mut r).unwrap(), None);
+
+        let mut r = Cursor::new(vec![0xff; 4]);
+        assert!(read_message(&mut r).is_err());
+
+        // 帧头被截断不是正常关闭
+        let mut r = Cursor::new(vec![0; 2]);
+        match read_message(&mut r) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            res => panic!("expect UnexpectedEof, got {:?}", res),
+        }
+
+        // 超过最大长度的消息不会被写出
+        let mut m = new_message(1, 2, MessageType::MsgAppend);
+        m.mut_entries().push(Entry {
+            data: vec![0; MAX_FRAME_SIZE],
+            ..Default::default()
+        });
+        let mut buf = vec![];
+        assert!(write_message(&mut buf, &m).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_transport_send() {
+        let logger = crate::default_logger();
+        let (mut t1, rx1) = Transport::new(1, &logger);
+        let addr = t1.listen("127.0.0.1:0").unwrap();
+        let (mut t2, _rx2) = Transport::new(2, &logger);
+        t2.add_peer(1, addr).unwrap();
+
+        for t in &[MessageType::MsgHeartbeat, MessageType::MsgAppend] {
+            t2.send(new_message(2, 1, *t)).unwrap();
+        }
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            rx1.recv_timeout(timeout).unwrap().get_msg_type(),
+            MessageType::MsgHeartbeat
+        );
+        assert_eq!(
+            rx1.recv_timeout(timeout).unwrap().get_msg_type(),
+            MessageType::MsgAppend
+        );
+
+        match t2.send(new_message(2, 3, MessageType::MsgAppend)) {
+            Err(Error::NotExists(3, _)) => {}
+            res => panic!("expect NotExists, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_transport_unreachable() {
+        let logger = crate::default_logger();
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (mut t1, rx1) = Transport::new(1, &logger);
+        t1.add_peer(2, addr).unwrap();
+        t1.send(new_message(1, 2, MessageType::MsgAppend)).unwrap();
+
+        let m = rx1.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(m.get_msg_type(), MessageType::MsgUnreachable);
+        assert_eq!((m.from, m.to), (2, 1));
+    }
+
+    #[test]
+    fn test_peer_worker_unreachable() {
+        let logger = crate::default_logger();
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (inbound, rx1) = mpsc::channel();
+        let mut worker = PeerWorker {
+            id: 1,
+            peer: 2,
+            addr,
+            conn: None,
+            inbound,
+            logger,
+        };
+        let (tx, rx) = mpsc::sync_channel(8);
+        for _ in 0..5 {
+            tx.send(new_message(1, 2, MessageType::MsgAppend)).unwrap();
+        }
+        drop(tx);
+        worker.run(rx);
+        drop(worker);
+
+        // 连接失败时整个队列只产生一条不可达消息
+        let msgs: Vec<Message> = rx1.iter().collect();
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].get_msg_type(), MessageType::MsgUnreachable);
+    }
+
+    #[test]
+    fn test_transport_shutdown() {
+        let logger = crate::default_logger();
+        let (mut t1, rx1) = Transport::new(1, &logger);
+        let addr = t1.listen("127.0.0.1:0").unwrap();
+        let (mut t2, _rx2) = Transport::new(2, &logger);
+        t2.add_peer(1, addr).unwrap();
+        t2.send(new_message(2, 1, MessageType::MsgHeartbeat))
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        assert!(rx1.recv_timeout(timeout).is_ok());
+
+        // 所有线程退出之后接收队列的发送端都被释放
+        drop(t1);
+        assert_eq!(
+            rx1.recv_timeout(timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+        assert!(TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_err());
+        t2.shutdown();
+    }
+}
@@ -3,7 +3,8 @@ use std::fmt;
 use std::fmt::Write;
 use std::u64;
 
-use crate::protos::eraftpb::{Entry, Message};
+use crate::protos::eraftpb::{Entry, Message, MessageType};
+use protobuf::Message as PbMessage;
 
 /// A number to represent that there is no limit.
 pub const NO_LIMIT: u64 = u64::MAX;
@@ -36,3 +37,150 @@ pub(crate) fn format_kv_list(kv_list: &OwnedKVList) -> String {
         .unwrap();
     formatter.buffer
 }
+
+/// 截断 `entries`，使其总长度不超过 `max`，但至少保留一个条目
+pub fn limit_size<T: PbMessage + Clone>(entries: &mut Vec<T>, max: Option<u64>) {
+    if entries.len() <= 1 {
+        return;
+    }
+    let max = match max {
+        None | Some(NO_LIMIT) => return,
+        Some(max) => max,
+    };
+
+    let mut size = 0;
+    let limit = entries
+        .iter()
+        .take_while(|&e| {
+            if size == 0 {
+                size += u64::from(e.compute_size());
+                true
+            } else {
+                size += u64::from(e.compute_size());
+                size <= max
+            }
+        })
+        .count();
+
+    entries.truncate(limit);
+}
+
+/// 计算日志条目序列化后的总长度
+pub fn entries_size(ents: &[Entry]) -> u64 {
+    ents.iter().map(|e| u64::from(e.compute_size())).sum()
+}
+
+/// 尝试把追加消息 `m` 合并到 `msgs` 中发往同一节点的最后一条消息中。
+/// 只有这条消息是追加消息、日志条目连续，并且合并后的长度不超过 `max_size` 时才会合并，
+/// 这样不会改变发往该节点的消息顺序。
+/// 合并成功返回 `None`，否则原样返回 `m`
+pub fn try_batch_append(msgs: &mut [Message], m: Message, max_size: u64) -> Option<Message> {
+    if m.get_msg_type() != MessageType::MsgAppend {
+        return Some(m);
+    }
+    let prev = match msgs.iter_mut().rev().find(|prev| prev.to == m.to) {
+        Some(prev) if prev.get_msg_type() == MessageType::MsgAppend => prev,
+        _ => return Some(m),
+    };
+    if prev.term != m.term {
+        return Some(m);
+    }
+    let last_index = match prev.get_entries().last() {
+        Some(e) => e.index,
+        None => prev.index,
+    };
+    if last_index != m.index {
+        return Some(m);
+    }
+    let size = entries_size(prev.get_entries()) + entries_size(m.get_entries());
+    if size > max_size {
+        return Some(m);
+    }
+    for e in m.get_entries() {
+        prev.mut_entries().push(e.clone());
+    }
+    prev.commit = m.commit;
+    None
+}
+
+/// 使用固定的随机种子驱动基于模型的测试。每个种子从 `new` 创建的状态开始，
+/// 执行若干个由 `random_op` 生成的操作，`apply` 负责执行操作并检查结果。
+/// 失败时输出种子以及已经执行的操作，便于复现
+#[cfg(test)]
+pub(crate) fn run_model_test<S, O, N, G, A>(new: N, random_op: G, apply: A)
+where
+    O: fmt::Debug + Clone,
+    N: Fn() -> S,
+    G: Fn(&mut rand::rngs::StdRng, &S) -> O,
+    A: Fn(&mut S, &O),
+{
+    use rand::SeedableRng;
+    use std::panic::{self, AssertUnwindSafe};
+
+    for seed in 0..200 {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut state = new();
+        let mut history = vec![];
+        for _ in 0..50 {
+            let op = random_op(&mut rng, &state);
+            history.push(op.clone());
+            let res = panic::catch_unwind(AssertUnwindSafe(|| apply(&mut state, &op)));
+            if res.is_err() {
+                panic!("seed {} failed after {:?}", seed, history);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_append(to: u64, index: u64, ents: &[u64], commit: u64) -> Message {
+        let mut m = Message::default();
+        m.set_msg_type(MessageType::MsgAppend);
+        m.to = to;
+        m.term = 1;
+        m.log_term = 1;
+        m.index = index;
+        m.commit = commit;
+        for &i in ents {
+            m.mut_entries().push(Entry {
+                index: i,
+                term: 1,
+                data: b"hello".to_vec(),
+                ..Default::default()
+            });
+        }
+        m
+    }
+
+    #[test]
+    fn test_try_batch_append() {
+        let mut msgs = vec![new_append(2, 1, &[2, 3], 1), new_append(3, 1, &[2, 3], 1)];
+        assert!(try_batch_append(&mut msgs, new_append(2, 3, &[4], 2), NO_LIMIT).is_none());
+        assert_eq!(msgs.len(), 2);
+        let indexes: Vec<u64> = msgs[0].get_entries().iter().map(|e| e.index).collect();
+        assert_eq!(indexes, vec![2, 3, 4]);
+        assert_eq!(msgs[0].commit, 2);
+
+        // 日志不连续
+        assert!(try_batch_append(&mut msgs, new_append(2, 5, &[6], 2), NO_LIMIT).is_some());
+        // 没有发往该节点的追加消息
+        assert!(try_batch_append(&mut msgs, new_append(4, 1, &[2], 1), NO_LIMIT).is_some());
+        // 超出长度限制
+        let limit = entries_size(msgs[1].get_entries());
+        assert!(try_batch_append(&mut msgs, new_append(3, 3, &[4], 1), limit).is_some());
+        assert_eq!(msgs[1].get_entries().len(), 2);
+        // max_size 为 0 时不会合并
+        assert!(try_batch_append(&mut msgs, new_append(3, 3, &[4], 1), 0).is_some());
+
+        // 追加消息之后还有发往同一节点的其他消息，合并会改变消息顺序
+        let mut heartbeat = Message::default();
+        heartbeat.set_msg_type(MessageType::MsgHeartbeat);
+        heartbeat.to = 2;
+        msgs.push(heartbeat);
+        assert!(try_batch_append(&mut msgs, new_append(2, 4, &[5], 2), NO_LIMIT).is_some());
+        assert_eq!(msgs[0].get_entries().len(), 3);
+    }
+}